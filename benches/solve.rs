@@ -0,0 +1,177 @@
+//! Measures what a solve request costs once the repodata is cached, on a generated channel about
+//! the size of conda-forge's linux-64 repodata.
+//!
+//! `shared` is what the server does now: the cached records are shared through an `Arc` and only
+//! the ones reachable from the specs are handed to the solver. `copied` is what it used to do: every
+//! request copied the cached records and gave all of them to the solver.
+//!
+//! Run with `cargo bench --bench solve`.
+
+// The server is a binary, so the modules it needs are compiled into the benchmark instead. Cargo
+// builds benchmarks with `cfg(test)`, which also brings in their test modules without the tests.
+#![allow(dead_code, unused_imports)]
+
+#[path = "../src/auth.rs"]
+mod auth;
+#[path = "../src/available_packages_cache.rs"]
+mod available_packages_cache;
+#[path = "../src/cli.rs"]
+mod cli;
+#[path = "../src/config.rs"]
+mod config;
+#[path = "../src/conflicts.rs"]
+mod conflicts;
+#[path = "../src/credentials.rs"]
+mod credentials;
+#[path = "../src/dto.rs"]
+mod dto;
+#[path = "../src/error.rs"]
+mod error;
+#[path = "../src/generic_cache.rs"]
+mod generic_cache;
+#[path = "../src/solve.rs"]
+mod solve;
+#[path = "../src/tls.rs"]
+mod tls;
+
+use available_packages_cache::AvailablePackagesCache;
+use config::ChannelPolicy;
+use criterion::{criterion_group, criterion_main, Criterion};
+use dto::ChannelPriority;
+use rattler_conda_types::{Channel, ChannelConfig, GenericVirtualPackage, MatchSpec, Platform};
+use rattler_solve::{resolvo, SolverImpl, SolverTask};
+use serde_json::json;
+use solve::SolveOptions;
+use std::collections::BTreeMap;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+/// Every package has this many versions
+const VERSIONS: usize = 20;
+
+/// With `VERSIONS`, roughly the amount of records in conda-forge's linux-64 repodata
+const PACKAGES: usize = 20_000;
+
+/// Package `i` depends on packages `i / 2` and `i / 3`, so a request for a few packages reaches a
+/// small part of the channel, like real environments do
+fn repodata() -> String {
+    let mut packages = serde_json::Map::new();
+    for i in 0..PACKAGES {
+        let depends: Vec<_> = [i / 2, i / 3]
+            .into_iter()
+            .filter(|&dependency| dependency != i)
+            .map(|dependency| format!("pkg-{dependency} >=1"))
+            .collect();
+        for version in 1..=VERSIONS {
+            packages.insert(
+                format!("pkg-{i}-{version}.0-0.tar.bz2"),
+                json!({
+                    "build": "0",
+                    "build_number": 0,
+                    "depends": depends,
+                    "name": format!("pkg-{i}"),
+                    "subdir": "linux-64",
+                    "version": format!("{version}.0"),
+                }),
+            );
+        }
+    }
+
+    json!({
+        "info": { "subdir": "linux-64" },
+        "packages": packages,
+        "packages.conda": {},
+    })
+    .to_string()
+}
+
+fn solve_requests(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let mut server = mockito::Server::new();
+    server
+        .mock("GET", "/bench/linux-64/repodata.json")
+        .with_body(repodata())
+        .create();
+    server
+        .mock("GET", "/bench/noarch/repodata.json")
+        .with_body(r#"{ "info": { "subdir": "noarch" }, "packages": {} }"#)
+        .create();
+
+    let temp_dir = mktemp::Temp::new_dir().unwrap();
+    let cache = AvailablePackagesCache::new(
+        Duration::from_secs(3600),
+        temp_dir.to_path_buf(),
+        BTreeMap::new(),
+        ChannelPolicy::default(),
+        1,
+        None,
+    );
+    let channel =
+        Channel::from_str(format!("{}/bench", server.url()), &ChannelConfig::default()).unwrap();
+    let get = |platform| {
+        runtime
+            .block_on(cache.get(&channel, platform, None))
+            .unwrap()
+            .0
+    };
+
+    // Download and parse the repodata once, so only cache hits are measured
+    get(Platform::Linux64);
+    get(Platform::NoArch);
+
+    let specs: Vec<MatchSpec> = ["pkg-19999", "pkg-12345", "pkg-777"]
+        .into_iter()
+        .map(|spec| spec.parse().unwrap())
+        .collect();
+    let virtual_packages: Vec<GenericVirtualPackage> = Vec::new();
+
+    let mut group = c.benchmark_group("solve");
+    group.sample_size(10);
+
+    group.bench_function("shared", |b| {
+        b.iter(|| {
+            let available_packages = vec![get(Platform::Linux64), get(Platform::NoArch)];
+            let options = SolveOptions {
+                timeout: Duration::from_secs(600),
+                minimize_conflicts: false,
+                published_before: None,
+                exclude_undated: false,
+                excluded_specs: Vec::new(),
+                channel_priority: ChannelPriority::Flexible,
+                cancelled: CancellationToken::new(),
+            };
+            solve::solve(
+                resolvo::Solver,
+                &available_packages,
+                specs.clone(),
+                virtual_packages.clone(),
+                &options,
+            )
+            .unwrap()
+        })
+    });
+
+    group.bench_function("copied", |b| {
+        b.iter(|| {
+            let available_packages = vec![
+                get(Platform::Linux64).records.to_vec(),
+                get(Platform::NoArch).records.to_vec(),
+            ];
+            resolvo::Solver
+                .solve(SolverTask {
+                    available_packages: &available_packages,
+                    specs: specs.clone(),
+                    virtual_packages: virtual_packages.clone(),
+                    locked_packages: Vec::new(),
+                    pinned_packages: Vec::new(),
+                    timeout: None,
+                })
+                .unwrap()
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, solve_requests);
+criterion_main!(benches);
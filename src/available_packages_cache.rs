@@ -1,54 +1,160 @@
+use crate::config::{ChannelPolicy, ChannelSettings, Credentials};
+use crate::credentials::CredentialsMiddleware;
 use crate::error::ApiError;
+use crate::solve::PackageIndex;
 use anyhow::Context;
+use arc_swap::ArcSwap;
+use chrono::{DateTime, Utc};
 use rattler_conda_types::{Channel, Platform, RepoData, RepoDataRecord};
 use rattler_repodata_gateway::fetch;
 use reqwest::Url;
 use reqwest_middleware::ClientWithMiddleware;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 use std::{default::Default, path::PathBuf};
+use tokio::sync::Semaphore;
 use tracing::{span, Instrument, Level};
 
 use crate::generic_cache::{GenericCache, GetCachedResult};
 
+/// The packages of a (channel, platform) pair, as downloaded at some point. They are shared by all
+/// requests using them, so they are never copied.
+pub struct AvailablePackages {
+    pub records: Vec<RepoDataRecord>,
+    /// Identifies this version of the repodata. It changes whenever the repodata might have
+    /// changed, so it can be used to invalidate anything derived from the records.
+    pub revision: String,
+    /// Prepared once, so requests don't have to go through all records to find the ones they need
+    pub index: PackageIndex,
+    /// Where and when the repodata was downloaded, as reported to clients
+    pub info: RepoDataInfo,
+}
+
+impl AvailablePackages {
+    pub fn new(
+        records: Vec<RepoDataRecord>,
+        revision: String,
+        info: RepoDataInfo,
+    ) -> AvailablePackages {
+        AvailablePackages {
+            index: PackageIndex::new(&records),
+            records,
+            revision,
+            info,
+        }
+    }
+}
+
+/// Tells which version of a repodata file was used
+#[cfg_attr(test, derive(serde::Deserialize))]
+#[derive(Clone, Debug, Serialize)]
+pub struct RepoDataInfo {
+    /// Where the repodata was downloaded from, which may be a mirror
+    pub url: String,
+    pub fetched_at: DateTime<Utc>,
+    /// The `ETag` header of the repodata, if the channel sent one
+    pub etag: Option<String>,
+    /// The `Last-Modified` header of the repodata, if the channel sent one
+    pub last_modified: Option<String>,
+}
+
 /// Caches the available packages for (channel, platform) pairs
 pub struct AvailablePackagesCache {
-    cache: GenericCache<Url, Vec<RepoDataRecord>>,
+    cache: GenericCache<Url, AvailablePackages>,
+    /// Distinguishes downloads of repodata that has no ETag
+    downloads: AtomicU64,
+    /// Limits the amount of repodata files parsed at the same time, across all requests
+    parse_permits: Arc<Semaphore>,
     cache_dir: PathBuf,
     download_client: ClientWithMiddleware,
+    /// The credentials used by `download_client`
+    credentials: Arc<ArcSwap<BTreeMap<String, Credentials>>>,
+    /// The policy every redirect followed by `download_client` must satisfy
+    channel_policy: Arc<ArcSwap<ChannelPolicy>>,
 }
 
 impl AvailablePackagesCache {
-    /// Creates an empty `AvailablePackagesCache` with keys that expire after `expiration`
-    pub fn new(expiration: Duration, cache_dir: PathBuf) -> AvailablePackagesCache {
+    /// Creates an empty `AvailablePackagesCache` with keys that expire after `expiration`. The
+    /// credentials are used to download repodata from the hosts they are configured for, and
+    /// redirects are only followed if the channel policy allows their target. At most
+    /// `parse_workers` downloaded files are parsed at the same time, and downloads taking longer
+    /// than `download_timeout` fail.
+    pub fn new(
+        expiration: Duration,
+        cache_dir: PathBuf,
+        credentials: BTreeMap<String, Credentials>,
+        channel_policy: ChannelPolicy,
+        parse_workers: usize,
+        download_timeout: Option<Duration>,
+    ) -> AvailablePackagesCache {
+        let credentials = Arc::new(ArcSwap::from_pointee(credentials));
+        let channel_policy = Arc::new(ArcSwap::from_pointee(channel_policy));
         AvailablePackagesCache {
             cache: GenericCache::with_expiration(expiration),
-            download_client: ClientWithMiddleware::new(reqwest::Client::new(), []),
+            downloads: AtomicU64::new(0),
+            parse_permits: Arc::new(Semaphore::new(parse_workers)),
+            download_client: ClientWithMiddleware::new(
+                download_client(download_timeout, channel_policy.clone()),
+                vec![Arc::new(CredentialsMiddleware::new(credentials.clone())) as _],
+            ),
+            credentials,
+            channel_policy,
             cache_dir,
         }
     }
 
+    /// Applies new settings without clearing the cache. Entries that are already cached keep
+    /// their expiration.
+    pub fn reconfigure(
+        &self,
+        expiration: Duration,
+        credentials: BTreeMap<String, Credentials>,
+        channel_policy: ChannelPolicy,
+    ) {
+        self.cache.set_expiration(expiration);
+        self.credentials.store(Arc::new(credentials));
+        self.channel_policy.store(Arc::new(channel_policy));
+    }
+
     /// Removes outdated data from the cache
     pub fn gc(&self) {
         self.cache.gc();
     }
 
+    /// Waits for the repodata downloads that are in progress, so they are fully written to the
+    /// on-disk cache
+    pub async fn flush(&self) {
+        self.cache.wait_for_active_writes().await;
+    }
+
     /// Gets the repo data for this channel and platform if they exist in the cache, and downloads
-    /// them otherwise
+    /// them otherwise. The channel settings determine where the repodata is downloaded from and
+    /// how long it is cached. Also tells whether the repodata was found in the cache.
     pub async fn get(
         &self,
         channel: &Channel,
         platform: Platform,
-    ) -> Result<Vec<RepoDataRecord>, ApiError> {
+        settings: Option<&ChannelSettings>,
+    ) -> Result<(Arc<AvailablePackages>, bool), ApiError> {
         let platform_url = channel.platform_url(platform);
         let write_token = match self.cache.get_cached(&platform_url).await {
-            GetCachedResult::Found(repodata) => return Ok(repodata.to_vec()),
+            GetCachedResult::Found(repodata) => return Ok((repodata, true)),
             GetCachedResult::NotFound(write_guard) => write_guard,
         };
 
-        // Download
+        // Download, possibly from a mirror
+        let download_url = match settings.and_then(|s| s.mirror.as_ref()) {
+            Some(mirror) => mirror
+                .join(&format!("{}/", platform.as_str()))
+                .context("invalid mirror url")
+                .map_err(ApiError::Internal)?,
+            None => channel.platform_url(platform),
+        };
         let result = fetch::fetch_repo_data(
-            channel.platform_url(platform),
+            download_url.clone(),
             self.download_client.clone(),
             self.cache_dir.clone(),
             fetch::FetchRepoDataOptions {
@@ -58,15 +164,153 @@ impl AvailablePackagesCache {
         )
         .instrument(span!(Level::DEBUG, "fetch_repo_data"))
         .await
-        .map_err(|err| ApiError::FetchRepoDataJson(channel.platform_url(platform), err))?;
+        .map_err(|err| ApiError::FetchRepoDataJson(download_url.clone(), err))?;
 
-        let repodata = RepoData::from_path(result.repo_data_json_path)
-            .context("loading repo data")
-            .map_err(ApiError::Internal)?
-            .into_repo_data_records(channel);
+        // The ETag only changes along with the contents, so re-downloading unchanged repodata
+        // keeps the revision. Without an ETag, every download counts as a new revision.
+        let revision = match &result.cache_state.cache_headers.etag {
+            Some(etag) => format!("{platform_url} etag {etag}"),
+            None => format!(
+                "{platform_url} download {}",
+                self.downloads.fetch_add(1, Ordering::Relaxed)
+            ),
+        };
+
+        let info = RepoDataInfo {
+            url: download_url.to_string(),
+            fetched_at: Utc::now(),
+            etag: result.cache_state.cache_headers.etag.clone(),
+            last_modified: result.cache_state.cache_headers.last_modified.clone(),
+        };
+
+        // Parsing takes long enough to stall the runtime's worker threads, so it happens on the
+        // blocking pool, limited to a few files at a time to leave CPU for the solver
+        let permit = self
+            .parse_permits
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("the semaphore is never closed");
+        let channel = channel.clone();
+        let repodata = tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            let records =
+                RepoData::from_path(result.repo_data_json_path)?.into_repo_data_records(&channel);
+            Ok::<_, std::io::Error>(AvailablePackages::new(records, revision, info))
+        })
+        .instrument(span!(Level::DEBUG, "parse_repo_data"))
+        .await
+        .context("repodata parse task panicked")
+        .map_err(ApiError::Internal)?
+        .map_err(|err| match err.kind() {
+            // Invalid or truncated JSON is the channel's fault, anything else is a problem on our side
+            std::io::ErrorKind::InvalidData | std::io::ErrorKind::UnexpectedEof => {
+                ApiError::InvalidRepoData(download_url, err)
+            }
+            _ => ApiError::Internal(anyhow::Error::new(err).context("loading repo data")),
+        })?;
+        let repodata = Arc::new(repodata);
 
         // Update the cache
-        self.cache.set(write_token, Arc::new(repodata.clone()));
-        Result::Ok(repodata)
+        let value = repodata.clone();
+        match settings.and_then(|s| s.cache_expiration_seconds) {
+            Some(seconds) => {
+                self.cache
+                    .set_with_expiration(write_token, value, Duration::from_secs(seconds))
+            }
+            None => self.cache.set(write_token, value),
+        }
+        Result::Ok((repodata, false))
+    }
+}
+
+/// How long to wait for a channel's server to accept the connection
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The amount of redirects followed for a single download, like reqwest's default policy
+const MAX_REDIRECTS: usize = 10;
+
+fn download_client(
+    timeout: Option<Duration>,
+    channel_policy: Arc<ArcSwap<ChannelPolicy>>,
+) -> reqwest::Client {
+    // Channels are checked against the policy before they are fetched, but a channel (or mirror)
+    // could still redirect the server to a host it should never talk to
+    let redirect_policy = reqwest::redirect::Policy::custom(move |attempt| {
+        if attempt.previous().len() >= MAX_REDIRECTS {
+            return attempt.error("too many redirects");
+        }
+        match channel_policy.load().check_download(attempt.url()) {
+            Ok(()) => attempt.follow(),
+            Err(reason) => attempt.error(format!("redirect not allowed: {reason}")),
+        }
+    });
+
+    let mut builder = reqwest::Client::builder()
+        .connect_timeout(CONNECT_TIMEOUT)
+        .redirect(redirect_policy);
+    if let Some(timeout) = timeout {
+        builder = builder.timeout(timeout);
+    }
+    builder
+        .build()
+        .expect("the download client can always be built")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rattler_conda_types::ChannelConfig;
+
+    #[tokio::test]
+    async fn test_cached_records_are_shared() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("GET", "/conda-forge/linux-64/repodata.json")
+            .with_body(
+                r#"{
+                  "info": { "subdir": "linux-64" },
+                  "packages": {
+                    "foo-1.0-0.tar.bz2": {
+                      "build": "0",
+                      "build_number": 0,
+                      "depends": [],
+                      "name": "foo",
+                      "subdir": "linux-64",
+                      "version": "1.0"
+                    }
+                  },
+                  "packages.conda": {}
+                }"#,
+            )
+            .expect(1)
+            .create_async()
+            .await;
+
+        let temp_dir = mktemp::Temp::new_dir().unwrap();
+        let cache = AvailablePackagesCache::new(
+            Duration::from_secs(60),
+            temp_dir.to_path_buf(),
+            BTreeMap::new(),
+            ChannelPolicy::default(),
+            1,
+            None,
+        );
+        let channel = Channel::from_str(
+            format!("{}/conda-forge", server.url()),
+            &ChannelConfig::default(),
+        )
+        .unwrap();
+
+        let (first, cache_hit) = cache.get(&channel, Platform::Linux64, None).await.unwrap();
+        assert!(!cache_hit);
+        assert_eq!(first.records.len(), 1);
+
+        // Every request gets the same records, instead of a copy of them
+        let (second, cache_hit) = cache.get(&channel, Platform::Linux64, None).await.unwrap();
+        assert!(cache_hit);
+        assert!(Arc::ptr_eq(&first, &second));
+        assert_eq!(first.records.as_ptr(), second.records.as_ptr());
+        mock.assert_async().await;
     }
 }
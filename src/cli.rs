@@ -1,24 +1,50 @@
+use std::net::{IpAddr, Ipv4Addr};
 use std::path::PathBuf;
 
 use clap::Parser;
+use serde::{Deserialize, Serialize};
 
-#[derive(Parser)]
+use crate::tls::TlsPaths;
+
+pub const DEFAULT_HOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+pub const DEFAULT_PORT: u16 = 3000;
+pub const DEFAULT_CONCURRENT_REPODATA_DOWNLOADS_PER_REQUEST: usize = 8;
+pub const DEFAULT_REPODATA_CACHE_EXPIRATION_SECONDS: u64 = 30 * 60;
+pub const DEFAULT_SHUTDOWN_GRACE_PERIOD_SECONDS: u64 = 30;
+
+/// Command line arguments. Settings passed here (or through their environment variable) take
+/// precedence over the config file.
+#[derive(Parser, Clone)]
 pub struct Args {
+    /// A TOML or YAML file with the server configuration. Settings that are also passed on the
+    /// command line or through the environment are overridden.
+    #[arg(long, env = "RATTLER_SERVER_CONFIG", value_hint = clap::ValueHint::FilePath)]
+    pub config: Option<PathBuf>,
+
+    /// Print the effective configuration, with secrets redacted, and exit.
+    #[arg(long)]
+    pub print_config: bool,
+
+    /// The address at which the server should listen. Use `0.0.0.0` (or `::`) to accept
+    /// connections from other machines.
+    #[arg(long, default_value_t = DEFAULT_HOST, env = "RATTLER_SERVER_HOST")]
+    pub host: IpAddr,
+
     /// The port at which the server should listen
-    #[arg(short, default_value_t = 3000, env = "RATTLER_SERVER_PORT")]
+    #[arg(short, default_value_t = DEFAULT_PORT, env = "RATTLER_SERVER_PORT")]
     pub port: u16,
 
-    /// The amount of concurrent downloads of repodata.json files, during a single request. JSON
-    /// downloads are very CPU-intensive, because they require parsing huge JSON bodies.
+    /// The amount of concurrent downloads of repodata.json files, during a single request. Parsing
+    /// the downloaded JSON is limited separately, by the amount of repodata parse threads.
     #[arg(
         short,
-        default_value_t = 1,
+        default_value_t = DEFAULT_CONCURRENT_REPODATA_DOWNLOADS_PER_REQUEST,
         env = "RATTLER_SERVER_PORT_CONCURRENT_DOWNLOADS"
     )]
     pub concurrent_repodata_downloads_per_request: usize,
 
     /// The amount of seconds after which a cached repodata.json expires, defaults to 30 minutes.
-    #[arg(short, default_value_t = 30 * 60, env = "RATTLER_SERVER_CACHE_EXPIRATION_SECONDS")]
+    #[arg(short, default_value_t = DEFAULT_REPODATA_CACHE_EXPIRATION_SECONDS, env = "RATTLER_SERVER_CACHE_EXPIRATION_SECONDS")]
     pub repodata_cache_expiration_seconds: u64,
 
     /// The directory to store cached repodata.json files in.
@@ -28,16 +54,56 @@ pub struct Args {
     /// The solver implementation to use.
     #[arg(long, value_enum, default_value_t, env = "RATTLER_SOLVER")]
     pub solver: Solver,
+
+    /// The amount of seconds in-flight requests get to finish after receiving SIGTERM or SIGINT.
+    /// Requests still running after that are cancelled.
+    #[arg(
+        long,
+        default_value_t = DEFAULT_SHUTDOWN_GRACE_PERIOD_SECONDS,
+        env = "RATTLER_SERVER_SHUTDOWN_GRACE_PERIOD_SECONDS"
+    )]
+    pub shutdown_grace_period_seconds: u64,
+
+    /// PEM-encoded certificate chain. When provided together with `--tls-key`, the server accepts
+    /// HTTPS connections instead of plain HTTP. Send SIGHUP to reload the certificates from disk.
+    #[arg(long, requires = "tls_key", env = "RATTLER_SERVER_TLS_CERT", value_hint = clap::ValueHint::FilePath)]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM-encoded private key belonging to the certificate passed to `--tls-cert`.
+    #[arg(long, requires = "tls_cert", env = "RATTLER_SERVER_TLS_KEY", value_hint = clap::ValueHint::FilePath)]
+    pub tls_key: Option<PathBuf>,
+
+    /// PEM-encoded CA certificates used to verify client certificates. When provided, only clients
+    /// presenting a certificate signed by one of these CAs can connect (mTLS).
+    #[arg(long, requires = "tls_cert", env = "RATTLER_SERVER_TLS_CLIENT_CA", value_hint = clap::ValueHint::FilePath)]
+    pub tls_client_ca: Option<PathBuf>,
 }
 
-#[derive(Clone, clap::ValueEnum, Default, Copy)]
+#[derive(
+    Clone, clap::ValueEnum, Default, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
 pub enum Solver {
     #[default]
     Resolvo,
     Libsolvc,
 }
 
-fn get_default_cache_dir() -> PathBuf {
+impl Args {
+    /// The TLS files to use, if the server should terminate TLS itself
+    pub fn tls_paths(&self) -> Option<TlsPaths> {
+        match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) => Some(TlsPaths {
+                cert: cert.clone(),
+                key: key.clone(),
+                client_ca: self.tls_client_ca.clone(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+pub fn get_default_cache_dir() -> PathBuf {
     let mut path = dirs::cache_dir().unwrap();
     path.push("rattler");
     path
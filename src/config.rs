@@ -0,0 +1,755 @@
+//! Contains the server configuration, which is assembled from the config file, the environment and
+//! the command line
+
+use crate::cli::{self, Args, Solver};
+use crate::tls::TlsPaths;
+use anyhow::Context;
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches};
+use rattler_conda_types::{Channel, ChannelConfig, MatchSpec, ParseChannelError, Platform};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// The placeholder shown instead of secrets when printing the configuration
+const REDACTED: &str = "********";
+
+/// The effective configuration of the server
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub host: IpAddr,
+    pub port: u16,
+    pub concurrent_repodata_downloads_per_request: usize,
+    pub repodata_cache_expiration_seconds: u64,
+    pub cache_dir: PathBuf,
+    pub solver: Solver,
+    pub shutdown_grace_period_seconds: u64,
+    /// The URL that channel names (e.g. `conda-forge`) are resolved against
+    pub channel_alias: Url,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls: Option<TlsPaths>,
+    /// Per-channel settings, keyed by the channel name or URL as it appears in requests
+    pub channels: BTreeMap<String, ChannelSettings>,
+    /// Credentials used to download repodata, keyed by host name
+    pub credentials: BTreeMap<String, Credentials>,
+    /// Whether to expose the `/admin` endpoints, e.g. to reload the configuration
+    pub admin_api: bool,
+    /// Restricts the channels that requests may use
+    pub channel_policy: ChannelPolicy,
+    /// Limits how many requests each client can make
+    pub rate_limits: RateLimits,
+    /// Requires clients to authenticate. Disabled unless API keys or a JWKS file are configured.
+    pub auth: AuthConfig,
+    /// The amount of threads that run solves. Defaults to the amount of CPUs.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub solver_workers: Option<usize>,
+    /// The amount of solves that can wait for a solver thread. Requests beyond that are rejected
+    /// until the queue drains.
+    pub solver_queue_size: usize,
+    /// The amount of repodata files that can be parsed at the same time, across all requests.
+    /// Defaults to the amount of CPUs.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub repodata_parse_workers: Option<usize>,
+    /// How long solve results are cached. Results are never reused once the repodata they were
+    /// computed from changes. Set to 0 to disable the cache.
+    pub solve_cache_expiration_seconds: u64,
+    /// How long the solves of a request may take together. Only resolvo gives up on a solve that
+    /// runs over it, libsolv finishes the solve but no further solves are started for the request.
+    pub solve_timeout_seconds: u64,
+    /// How long downloading a repodata file may take. Set to 0 to wait indefinitely.
+    pub repodata_download_timeout_seconds: u64,
+    /// Solve without the channels whose repodata cannot be fetched, as if they were optional
+    pub skip_unavailable_channels: bool,
+    /// Solve without the `noarch` platform of channels that do not have one
+    pub skip_missing_noarch: bool,
+    /// Leave out packages published less than this many days ago. Set to 0 to allow all packages.
+    pub minimum_package_age_days: u32,
+    /// Let packages without a timestamp through the minimum package age. They are left out by
+    /// default, because their age is unknown.
+    pub allow_undated_packages: bool,
+    /// Match specs of packages and builds that are never used, e.g. known-bad uploads
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub denied_packages: Vec<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            host: cli::DEFAULT_HOST,
+            port: cli::DEFAULT_PORT,
+            concurrent_repodata_downloads_per_request:
+                cli::DEFAULT_CONCURRENT_REPODATA_DOWNLOADS_PER_REQUEST,
+            repodata_cache_expiration_seconds: cli::DEFAULT_REPODATA_CACHE_EXPIRATION_SECONDS,
+            cache_dir: cli::get_default_cache_dir(),
+            solver: Solver::default(),
+            shutdown_grace_period_seconds: cli::DEFAULT_SHUTDOWN_GRACE_PERIOD_SECONDS,
+            channel_alias: ChannelConfig::default().channel_alias,
+            tls: None,
+            channels: BTreeMap::new(),
+            credentials: BTreeMap::new(),
+            admin_api: false,
+            channel_policy: ChannelPolicy::default(),
+            rate_limits: RateLimits::default(),
+            auth: AuthConfig::default(),
+            solver_workers: None,
+            solver_queue_size: 64,
+            repodata_parse_workers: None,
+            solve_cache_expiration_seconds: 600,
+            solve_timeout_seconds: 20,
+            repodata_download_timeout_seconds: 300,
+            skip_unavailable_channels: false,
+            skip_missing_noarch: false,
+            minimum_package_age_days: 0,
+            allow_undated_packages: false,
+            denied_packages: Vec::new(),
+        }
+    }
+}
+
+/// Settings that apply to a single channel
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChannelSettings {
+    /// Where the channel is hosted. Setting this maps the channel's name to an arbitrary URL.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<Url>,
+    /// A URL with the same layout as the channel, from which repodata is downloaded instead
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mirror: Option<Url>,
+    /// Overrides `repodata_cache_expiration_seconds` for this channel
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cache_expiration_seconds: Option<u64>,
+    /// Platforms whose repodata is downloaded at startup and kept in the cache from then on
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub prewarm: Vec<Platform>,
+}
+
+/// Restricts which channels requests may use, so clients cannot make the server fetch arbitrary
+/// URLs. Patterns may contain `*`, which matches any sequence of characters.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChannelPolicy {
+    /// Patterns for the channel URLs (without trailing slash) that requests may use. When empty,
+    /// all channels are allowed unless their host is denied.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub allowed: Vec<String>,
+    /// Patterns for hosts that requests may never use, even if the channel URL is allowed
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub denied_hosts: Vec<String>,
+}
+
+impl ChannelPolicy {
+    /// Checks whether requests may use the channel, returning the reason if they may not
+    pub fn check(&self, channel: &Channel) -> Result<(), String> {
+        self.check_host(&channel.base_url)?;
+
+        let url = channel.base_url.as_str().trim_end_matches('/');
+        let allowed = self.allowed.iter().any(|p| glob_matches(p, url));
+        if !self.allowed.is_empty() && !allowed {
+            return Err(format!(
+                "the channel {url} is not in the list of allowed channels"
+            ));
+        }
+
+        check_scheme(&channel.base_url, allowed)
+    }
+
+    /// Checks whether the server may download from the URL, e.g. a mirror or the target of a
+    /// redirect, returning the reason if it may not. When channels are restricted, the URL must be
+    /// inside one of the allowed channels.
+    pub fn check_download(&self, url: &Url) -> Result<(), String> {
+        self.check_host(url)?;
+
+        let inside_allowed = |pattern: &String| {
+            let pattern = pattern.trim_end_matches('/');
+            glob_matches(pattern, url.as_str().trim_end_matches('/'))
+                || glob_matches(&format!("{pattern}/*"), url.as_str())
+        };
+        let allowed = self.allowed.iter().any(inside_allowed);
+        if !self.allowed.is_empty() && !allowed {
+            return Err(format!(
+                "the URL {url} is not inside any of the allowed channels"
+            ));
+        }
+
+        check_scheme(url, allowed)
+    }
+
+    fn check_host(&self, url: &Url) -> Result<(), String> {
+        if let Some(host) = url.host_str() {
+            let host = host.to_lowercase();
+            if let Some(pattern) = self
+                .denied_hosts
+                .iter()
+                .find(|pattern| glob_matches(&pattern.to_lowercase(), &host))
+            {
+                return Err(format!("the host {host} is denied by pattern {pattern}"));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Only lets through HTTP(S) URLs, unless the URL matches one of the allowed patterns. Other URLs,
+/// such as the `file://` URLs that local paths turn into, have no host to deny and would make the
+/// server read its own disk.
+fn check_scheme(url: &Url, allowed: bool) -> Result<(), String> {
+    match url.scheme() {
+        "http" | "https" => Ok(()),
+        _ if allowed => Ok(()),
+        scheme => Err(format!(
+            "{scheme} URLs are only allowed when they are in the list of allowed channels"
+        )),
+    }
+}
+
+/// Limits applied to each client, identified by its authenticated identity or else by IP address
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimits {
+    /// The amount of requests per second a client can make in the long run. Unlimited if absent.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub requests_per_second: Option<f64>,
+    /// The amount of requests a client can make in a burst, before being limited to
+    /// `requests_per_second`
+    pub burst: u32,
+    /// The amount of solves a client can have in flight at the same time. Unlimited if absent.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_concurrent_solves: Option<usize>,
+}
+
+impl RateLimits {
+    /// Rejects rates that a token bucket cannot refill at, which would make the time until the
+    /// next token infinite or negative
+    pub fn validate(&self) -> anyhow::Result<()> {
+        match self.requests_per_second {
+            Some(rate) if !(rate.is_finite() && rate > 0.0) => anyhow::bail!(
+                "invalid rate_limits.requests_per_second: {rate}, it must be a positive number"
+            ),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// How clients authenticate
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// Static API keys, keyed by the identity of the client using them
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub api_keys: BTreeMap<String, String>,
+    /// A JSON Web Key Set used to validate JWTs sent as bearer tokens. The `sub` claim of a valid
+    /// token is used as the identity of the client.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub jwks_file: Option<PathBuf>,
+    /// If present, the `iss` claim JWTs must have
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub jwt_issuer: Option<String>,
+    /// If present, the `aud` claim JWTs must have
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub jwt_audience: Option<String>,
+    /// The identities that may use the `/admin` endpoints. Everyone may use them when
+    /// authentication is disabled, because clients have no identity then.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub admins: Vec<String>,
+}
+
+/// Matches `text` against a pattern in which `*` matches any sequence of characters
+fn glob_matches(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+
+    // Can unwrap because split always returns at least one element
+    let first = parts.next().unwrap();
+    let Some(mut rest) = text.strip_prefix(first) else {
+        return false;
+    };
+
+    let parts: Vec<_> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        // The pattern has no wildcards
+        return rest.is_empty();
+    };
+
+    for part in middle {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+
+    rest.ends_with(last)
+}
+
+/// Credentials for a host serving channels
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum Credentials {
+    BearerToken { token: String },
+    BasicHttp { username: String, password: String },
+}
+
+impl Config {
+    /// Builds the effective configuration. Each setting is taken from the first of the following
+    /// sources that provides it: the command line, the environment, the config file and, lastly,
+    /// the built-in defaults.
+    pub fn load(args: &Args, matches: &ArgMatches) -> anyhow::Result<Config> {
+        let mut config = match &args.config {
+            Some(path) => Config::from_file(path)?,
+            None => Config::default(),
+        };
+
+        let is_explicit = |id: &str| {
+            matches!(
+                matches.value_source(id),
+                Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+            )
+        };
+
+        if is_explicit("host") {
+            config.host = args.host;
+        }
+        if is_explicit("port") {
+            config.port = args.port;
+        }
+        if is_explicit("concurrent_repodata_downloads_per_request") {
+            config.concurrent_repodata_downloads_per_request =
+                args.concurrent_repodata_downloads_per_request;
+        }
+        if is_explicit("repodata_cache_expiration_seconds") {
+            config.repodata_cache_expiration_seconds = args.repodata_cache_expiration_seconds;
+        }
+        if is_explicit("cache_dir") {
+            config.cache_dir = args.cache_dir.clone();
+        }
+        if is_explicit("solver") {
+            config.solver = args.solver;
+        }
+        if is_explicit("shutdown_grace_period_seconds") {
+            config.shutdown_grace_period_seconds = args.shutdown_grace_period_seconds;
+        }
+        if let Some(tls_paths) = args.tls_paths() {
+            config.tls = Some(tls_paths);
+        }
+
+        config.rate_limits.validate()?;
+        if config.solve_timeout_seconds == 0 {
+            anyhow::bail!("invalid solve_timeout_seconds: 0, it must be at least 1");
+        }
+        Ok(config)
+    }
+
+    /// The amount of solver threads to start
+    pub fn solver_workers(&self) -> usize {
+        self.solver_workers.unwrap_or_else(available_cpus)
+    }
+
+    /// The parsed `denied_packages`
+    pub fn denied_packages(&self) -> anyhow::Result<Vec<MatchSpec>> {
+        self.denied_packages
+            .iter()
+            .map(|spec| {
+                MatchSpec::from_str(spec)
+                    .with_context(|| format!("invalid spec in denied_packages: {spec}"))
+            })
+            .collect()
+    }
+
+    /// How long downloading a repodata file may take, if limited
+    pub fn repodata_download_timeout(&self) -> Option<Duration> {
+        match self.repodata_download_timeout_seconds {
+            0 => None,
+            seconds => Some(Duration::from_secs(seconds)),
+        }
+    }
+
+    /// The amount of repodata files to parse at the same time
+    pub fn repodata_parse_workers(&self) -> usize {
+        self.repodata_parse_workers
+            .unwrap_or_else(available_cpus)
+            .max(1)
+    }
+
+    /// Reads a TOML or YAML config file, depending on its extension
+    pub fn from_file(path: &Path) -> anyhow::Result<Config> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("unable to read config file {}", path.display()))?;
+
+        let config = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("yaml" | "yml") => serde_yaml::from_str(&contents).map_err(anyhow::Error::from),
+            _ => toml::from_str(&contents).map_err(anyhow::Error::from),
+        };
+
+        config.with_context(|| format!("invalid config file {}", path.display()))
+    }
+
+    /// The settings that differ from `new` but are only read at startup, so reloading the
+    /// configuration cannot apply them
+    pub fn restart_only_changes(&self, new: &Config) -> Vec<&'static str> {
+        [
+            ("host", self.host != new.host),
+            ("port", self.port != new.port),
+            ("cache_dir", self.cache_dir != new.cache_dir),
+            (
+                "shutdown_grace_period_seconds",
+                self.shutdown_grace_period_seconds != new.shutdown_grace_period_seconds,
+            ),
+            ("tls", self.tls != new.tls),
+            ("admin_api", self.admin_api != new.admin_api),
+            ("solver_workers", self.solver_workers != new.solver_workers),
+            (
+                "solver_queue_size",
+                self.solver_queue_size != new.solver_queue_size,
+            ),
+            (
+                "repodata_parse_workers",
+                self.repodata_parse_workers != new.repodata_parse_workers,
+            ),
+            (
+                "repodata_download_timeout_seconds",
+                self.repodata_download_timeout_seconds != new.repodata_download_timeout_seconds,
+            ),
+        ]
+        .into_iter()
+        .filter_map(|(name, changed)| changed.then_some(name))
+        .collect()
+    }
+
+    /// Returns a copy of the configuration that is safe to print, with all secrets replaced
+    pub fn redacted(&self) -> Config {
+        let mut config = self.clone();
+        for credentials in config.credentials.values_mut() {
+            match credentials {
+                Credentials::BearerToken { token } => *token = REDACTED.to_string(),
+                Credentials::BasicHttp { password, .. } => *password = REDACTED.to_string(),
+            }
+        }
+        for api_key in config.auth.api_keys.values_mut() {
+            *api_key = REDACTED.to_string();
+        }
+
+        config
+    }
+
+    /// Resolves the configured channels, so they can be matched against the channels in requests
+    pub fn channel_registry(&self) -> anyhow::Result<ChannelRegistry> {
+        let channel_config = ChannelConfig {
+            channel_alias: self.channel_alias.clone(),
+        };
+
+        let mut aliases = HashMap::new();
+        let mut channels = HashMap::new();
+        for (name, settings) in &self.channels {
+            let source = match &settings.url {
+                Some(url) => {
+                    aliases.insert(name.clone(), url.to_string());
+                    url.as_str()
+                }
+                None => name.as_str(),
+            };
+
+            let channel = Channel::from_str(source, &channel_config)
+                .with_context(|| format!("invalid channel in config file: {name}"))?;
+
+            let mut settings = settings.clone();
+            if let Some(mirror) = &mut settings.mirror {
+                // Platform URLs are joined onto the mirror, which only works with a trailing slash
+                if !mirror.path().ends_with('/') {
+                    mirror.set_path(&format!("{}/", mirror.path()));
+                }
+
+                self.channel_policy
+                    .check_download(mirror)
+                    .map_err(|e| anyhow::anyhow!("invalid mirror of channel {name}: {e}"))?;
+            }
+
+            channels.insert(
+                channel.base_url.clone(),
+                ConfiguredChannel { channel, settings },
+            );
+        }
+
+        Ok(ChannelRegistry {
+            channel_config,
+            aliases,
+            channels,
+        })
+    }
+}
+
+/// Where the configuration comes from, kept around so it can be loaded again when reloading
+#[derive(Clone)]
+pub struct ConfigSource {
+    args: Args,
+    matches: ArgMatches,
+}
+
+impl ConfigSource {
+    /// Parses the command line of the current process
+    pub fn from_command_line() -> anyhow::Result<ConfigSource> {
+        let matches = Args::command().get_matches();
+        ConfigSource::from_matches(matches)
+    }
+
+    fn from_matches(matches: ArgMatches) -> anyhow::Result<ConfigSource> {
+        let args = Args::from_arg_matches(&matches)?;
+        Ok(ConfigSource { args, matches })
+    }
+
+    #[cfg(test)]
+    pub fn from_argv<'a>(argv: impl IntoIterator<Item = &'a str>) -> ConfigSource {
+        let matches = Args::command().get_matches_from(argv);
+        ConfigSource::from_matches(matches).unwrap()
+    }
+
+    /// The parsed command line arguments
+    pub fn args(&self) -> &Args {
+        &self.args
+    }
+
+    /// Loads the configuration, reading the config file again if there is one
+    pub fn load(&self) -> anyhow::Result<Config> {
+        Config::load(&self.args, &self.matches)
+    }
+}
+
+/// A channel that has settings in the config file
+pub struct ConfiguredChannel {
+    pub channel: Channel,
+    pub settings: ChannelSettings,
+}
+
+/// Resolves the channels in requests, taking the channel settings from the config file into account
+pub struct ChannelRegistry {
+    channel_config: ChannelConfig,
+    aliases: HashMap<String, String>,
+    channels: HashMap<Url, ConfiguredChannel>,
+}
+
+impl ChannelRegistry {
+    /// Parses a channel as found in a request, resolving names that are mapped to a URL
+    pub fn parse(&self, channel: &str) -> Result<Channel, ParseChannelError> {
+        let source = self
+            .aliases
+            .get(channel)
+            .map(String::as_str)
+            .unwrap_or(channel);
+
+        Channel::from_str(source, &self.channel_config)
+    }
+
+    /// Returns the settings for the channel, if it has any
+    pub fn settings(&self, channel: &Channel) -> Option<&ChannelSettings> {
+        self.channels
+            .get(&channel.base_url)
+            .map(|configured| &configured.settings)
+    }
+
+    /// Iterates over the channels that have settings in the config file
+    pub fn configured_channels(&self) -> impl Iterator<Item = &ConfiguredChannel> {
+        self.channels.values()
+    }
+}
+
+/// The amount of CPUs the server can use, which sizes the thread pools by default
+fn available_cpus() -> usize {
+    std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(4)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    fn load(config_file: Option<&Path>, cli: &[&str]) -> Config {
+        let mut argv = vec!["rattler-server"];
+        let config_arg;
+        if let Some(path) = config_file {
+            config_arg = path.to_string_lossy().to_string();
+            argv.push("--config");
+            argv.push(&config_arg);
+        }
+        argv.extend_from_slice(cli);
+
+        ConfigSource::from_argv(argv).load().unwrap()
+    }
+
+    fn write_config(name: &str, contents: &str) -> (mktemp::Temp, PathBuf) {
+        let dir = mktemp::Temp::new_dir().unwrap();
+        let path = dir.to_path_buf().join(name);
+        std::fs::write(&path, contents).unwrap();
+        (dir, path)
+    }
+
+    #[test]
+    fn test_cli_takes_precedence_over_config_file() {
+        let (_dir, path) = write_config(
+            "config.toml",
+            "host = \"0.0.0.0\"\nport = 4000\nsolver = \"libsolvc\"\n",
+        );
+
+        let config = load(Some(&path), &["-p", "5000"]);
+        assert_eq!(config.port, 5000);
+        assert_eq!(config.host, IpAddr::from([0, 0, 0, 0]));
+        assert!(matches!(config.solver, Solver::Libsolvc));
+
+        // Settings that are not in the file or on the command line keep their default
+        assert_eq!(
+            config.repodata_cache_expiration_seconds,
+            cli::DEFAULT_REPODATA_CACHE_EXPIRATION_SECONDS
+        );
+    }
+
+    #[test]
+    fn test_invalid_rate_is_rejected() {
+        for rate in ["0", "-1", "nan", "inf"] {
+            let (_dir, path) = write_config(
+                "config.toml",
+                &format!("[rate_limits]\nrequests_per_second = {rate}\n"),
+            );
+
+            let source =
+                ConfigSource::from_argv(["rattler-server", "--config", path.to_str().unwrap()]);
+            let error = source.load().unwrap_err();
+            assert!(
+                error.to_string().contains("requests_per_second"),
+                "{error:#}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_zero_solve_timeout_is_rejected() {
+        let (_dir, path) = write_config("config.toml", "solve_timeout_seconds = 0\n");
+
+        let source =
+            ConfigSource::from_argv(["rattler-server", "--config", path.to_str().unwrap()]);
+        let error = source.load().unwrap_err();
+        assert!(
+            error.to_string().contains("solve_timeout_seconds"),
+            "{error:#}"
+        );
+    }
+
+    #[test]
+    fn test_yaml_config_file() {
+        let (_dir, path) = write_config(
+            "config.yaml",
+            "channels:\n  internal:\n    url: https://conda.example.com/internal\n    prewarm: [linux-64]\n",
+        );
+
+        let config = load(Some(&path), &[]);
+        let registry = config.channel_registry().unwrap();
+        let channel = registry.parse("internal").unwrap();
+        assert_eq!(
+            channel.base_url.as_str(),
+            "https://conda.example.com/internal/"
+        );
+        assert_eq!(
+            registry.settings(&channel).unwrap().prewarm,
+            vec![Platform::Linux64]
+        );
+    }
+
+    #[test]
+    fn test_glob_matches() {
+        assert!(glob_matches("conda.anaconda.org", "conda.anaconda.org"));
+        assert!(!glob_matches(
+            "conda.anaconda.org",
+            "conda.anaconda.org.evil.com"
+        ));
+        assert!(glob_matches("*.example.com", "conda.example.com"));
+        assert!(!glob_matches("*.example.com", "example.com"));
+        assert!(glob_matches(
+            "https://*/conda-*",
+            "https://mirror/conda-forge"
+        ));
+        assert!(!glob_matches(
+            "https://*/conda-*",
+            "http://mirror/conda-forge"
+        ));
+    }
+
+    #[test]
+    fn test_channel_policy() {
+        let policy = ChannelPolicy {
+            allowed: vec!["https://conda.anaconda.org/*".to_string()],
+            denied_hosts: vec!["*.internal".to_string()],
+        };
+        let channel = |source: &str| Channel::from_str(source, &ChannelConfig::default()).unwrap();
+
+        assert!(policy.check(&channel("conda-forge")).is_ok());
+        assert!(policy
+            .check(&channel("https://evil.com/conda-forge"))
+            .is_err());
+        assert!(policy
+            .check(&channel("https://metadata.internal/x"))
+            .is_err());
+    }
+
+    #[test]
+    fn test_channel_policy_local_paths() {
+        let channel = |source: &str| Channel::from_str(source, &ChannelConfig::default()).unwrap();
+        let local = channel("/etc/secrets");
+        assert_eq!(local.base_url.scheme(), "file");
+
+        // Local paths have no host, but are denied unless explicitly allowed
+        let policy = ChannelPolicy {
+            allowed: Vec::new(),
+            denied_hosts: vec!["169.254.169.254".to_string()],
+        };
+        assert!(policy.check(&local).is_err());
+        assert!(policy
+            .check_download(&Url::parse("file:///etc/secrets/linux-64/").unwrap())
+            .is_err());
+
+        let policy = ChannelPolicy {
+            allowed: vec!["file:///srv/channels/*".to_string()],
+            denied_hosts: Vec::new(),
+        };
+        assert!(policy.check(&channel("/srv/channels/internal")).is_ok());
+        assert!(policy.check(&local).is_err());
+    }
+
+    #[test]
+    fn test_channel_policy_check_download() {
+        let policy = ChannelPolicy {
+            allowed: vec!["https://conda.anaconda.org/conda-forge".to_string()],
+            denied_hosts: vec!["*.internal".to_string()],
+        };
+        let url = |url: &str| Url::parse(url).unwrap();
+
+        assert!(policy
+            .check_download(&url(
+                "https://conda.anaconda.org/conda-forge/linux-64/repodata.json"
+            ))
+            .is_ok());
+        assert!(policy
+            .check_download(&url("https://conda.anaconda.org/conda-forge-evil/"))
+            .is_err());
+        assert!(policy
+            .check_download(&url("https://metadata.internal/conda-forge/"))
+            .is_err());
+    }
+
+    #[test]
+    fn test_redacted_hides_secrets() {
+        let (_dir, path) = write_config(
+            "config.toml",
+            "[credentials.\"conda.example.com\"]\nusername = \"user\"\npassword = \"hunter2\"\n\
+             [auth.api_keys]\nteam-a = \"correct-horse\"\n",
+        );
+
+        let config = load(Some(&path), &[]);
+        let printed = toml::to_string_pretty(&config.redacted()).unwrap();
+        assert!(printed.contains("user"));
+        assert!(printed.contains("team-a"));
+        assert!(!printed.contains("hunter2"), "{printed}");
+        assert!(!printed.contains("correct-horse"), "{printed}");
+    }
+}
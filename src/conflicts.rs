@@ -0,0 +1,541 @@
+//! Contains the explanation of why an environment cannot be solved.
+//!
+//! The solver only reports unsolvable environments as text, so the structure is reconstructed here
+//! by following the requirements that are certain to apply: those of the requested specs, and the
+//! dependencies shared by every package that can satisfy one of them. Anything found this way is a
+//! genuine cause of the failure, but the solver might have found more.
+
+use crate::solve::Exclusion;
+use rattler_conda_types::{GenericVirtualPackage, MatchSpec, PackageRecord, RepoDataRecord};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+
+/// How many requirements to follow at most, to bound the time spent on huge dependency trees
+const MAX_REQUIREMENTS: usize = 10_000;
+
+/// How many specs and virtual packages a request can have at most to be minimized, since each of
+/// them takes an extra solve
+const MAX_MINIMIZED_ITEMS: usize = 32;
+
+/// The packages that satisfy a requirement, identified by their position among all records
+type Candidates = HashSet<usize>;
+
+/// Why an environment cannot be solved
+#[derive(Debug, Serialize)]
+pub struct Unsolvable {
+    /// The conflicts that were found, which may be fewer than the solver found
+    pub conflicts: Vec<Conflict>,
+    /// The conflicts rendered as plain text
+    pub explanation: String,
+    /// The messages reported by the solver itself
+    pub solver_messages: Vec<String>,
+    /// Only present if the client asked for it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub minimal_conflict: Option<MinimalConflict>,
+    /// The candidates that were left out before solving, which might have made it solvable
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub exclusions: Vec<Exclusion>,
+}
+
+/// A subset of the requested specs and virtual packages that cannot be solved, and that becomes
+/// solvable when leaving out any of its elements
+#[derive(Debug, Serialize)]
+pub struct MinimalConflict {
+    pub specs: Vec<String>,
+    pub virtual_packages: Vec<String>,
+    /// False if the search ran out of time, in which case the subset is still unsolvable but
+    /// might not be minimal
+    pub complete: bool,
+    /// A requested spec that makes the environment solvable when left out
+    pub suggested_relaxation: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Conflict {
+    /// No package satisfies the requirement
+    Missing { requirement: Requirement },
+    /// Every requirement can be satisfied on its own, but no version of the package satisfies all
+    /// of them at once
+    Clash {
+        package: String,
+        requirements: Vec<Requirement>,
+    },
+}
+
+/// A spec that has to be satisfied, and why
+#[derive(Clone, Debug, Serialize)]
+pub struct Requirement {
+    pub spec: String,
+    /// The requested spec this requirement comes from
+    pub root_spec: String,
+    /// The packages that lead from the requested spec to this requirement, in order
+    pub required_by: Vec<String>,
+}
+
+impl Display for Requirement {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.required_by.is_empty() {
+            return write!(f, "`{}` (requested)", self.spec);
+        }
+
+        write!(f, "`{}`, required by ", self.spec)?;
+        for package in self.required_by.iter().rev() {
+            write!(f, "{package} <- ")?;
+        }
+        write!(f, "`{}` (requested)", self.root_spec)
+    }
+}
+
+/// Explains why the specs cannot be solved with the given records and virtual packages
+pub fn explain(
+    available_packages: &[Vec<&RepoDataRecord>],
+    specs: &[MatchSpec],
+    virtual_packages: &[GenericVirtualPackage],
+    solver_messages: Vec<String>,
+) -> Unsolvable {
+    // Virtual packages can satisfy requirements just like the records
+    let virtual_records: Vec<_> = virtual_packages
+        .iter()
+        .map(|package| {
+            PackageRecord::new(
+                package.name.clone(),
+                package.version.clone(),
+                package.build_string.clone(),
+            )
+        })
+        .collect();
+
+    let records: Vec<&PackageRecord> = available_packages
+        .iter()
+        .flatten()
+        .map(|record| &record.package_record)
+        .chain(&virtual_records)
+        .collect();
+    let mut by_name: HashMap<&str, Vec<usize>> = HashMap::new();
+    for (i, record) in records.iter().enumerate() {
+        by_name
+            .entry(record.name.as_normalized())
+            .or_default()
+            .push(i);
+    }
+
+    let mut pending: VecDeque<_> = specs
+        .iter()
+        .map(|spec| {
+            let requirement = Requirement {
+                spec: spec.to_string(),
+                root_spec: spec.to_string(),
+                required_by: Vec::new(),
+            };
+            (spec.clone(), requirement)
+        })
+        .collect();
+    let mut seen: HashSet<String> = pending.iter().map(|(_, r)| r.spec.clone()).collect();
+
+    let mut conflicts = Vec::new();
+    let mut requirements_by_name: HashMap<String, Vec<(Requirement, Candidates)>> = HashMap::new();
+
+    while let Some((spec, requirement)) = pending.pop_front() {
+        let Some(name) = &spec.name else {
+            continue;
+        };
+
+        let candidates: Vec<usize> = by_name
+            .get(name.as_normalized())
+            .into_iter()
+            .flatten()
+            .copied()
+            .filter(|&i| spec.matches(records[i]))
+            .collect();
+
+        if candidates.is_empty() {
+            conflicts.push(Conflict::Missing { requirement });
+            continue;
+        }
+
+        // Dependencies that every candidate has apply no matter which candidate is picked
+        let shared_depends = records[candidates[0]].depends.iter().filter(|dependency| {
+            candidates[1..]
+                .iter()
+                .all(|&candidate| records[candidate].depends.contains(dependency))
+        });
+
+        let picked = match candidates.as_slice() {
+            [candidate] => records[*candidate].to_string(),
+            _ => format!("any of {} `{}` packages", candidates.len(), spec),
+        };
+        for dependency in shared_depends {
+            if seen.len() >= MAX_REQUIREMENTS || !seen.insert(dependency.clone()) {
+                continue;
+            }
+
+            if let Ok(dependency_spec) = MatchSpec::from_str(dependency) {
+                let mut required_by = requirement.required_by.clone();
+                required_by.push(picked.clone());
+                let dependency_requirement = Requirement {
+                    spec: dependency.clone(),
+                    root_spec: requirement.root_spec.clone(),
+                    required_by,
+                };
+                pending.push_back((dependency_spec, dependency_requirement));
+            }
+        }
+
+        let candidates = candidates.into_iter().collect();
+        requirements_by_name
+            .entry(name.as_normalized().to_string())
+            .or_default()
+            .push((requirement, candidates));
+    }
+
+    // Requirements on the same package clash if no candidate satisfies all of them
+    let mut requirements_by_name: Vec<_> = requirements_by_name.into_iter().collect();
+    requirements_by_name.sort_by(|(a, _), (b, _)| a.cmp(b));
+    let clashes = requirements_by_name
+        .into_iter()
+        .filter(|(_, requirements)| requirements.len() > 1)
+        .filter(|(_, requirements)| {
+            let mut common = requirements[0].1.clone();
+            for (_, candidates) in &requirements[1..] {
+                common.retain(|candidate| candidates.contains(candidate));
+            }
+            common.is_empty()
+        })
+        .map(|(package, requirements)| Conflict::Clash {
+            package,
+            requirements: requirements.into_iter().map(|(r, _)| r).collect(),
+        });
+    conflicts.extend(clashes);
+
+    Unsolvable {
+        explanation: render(&conflicts),
+        conflicts,
+        solver_messages,
+        minimal_conflict: None,
+        exclusions: Vec::new(),
+    }
+}
+
+/// Finds a minimal unsolvable subset of the specs and virtual packages, by leaving out one element
+/// at a time and keeping it out if the rest is still unsolvable. `is_solvable` runs the solver
+/// with the given timeout, returning `None` if it could not decide (e.g. because it timed out), in
+/// which case the element is kept.
+///
+/// All solves together must finish before `deadline`. Once it passes, or once `cancelled` is
+/// cancelled, no further solves are run and the subset found so far is returned. It is still
+/// unsolvable, but not necessarily minimal.
+///
+/// Returns `None` if there are too many specs and virtual packages to try.
+pub fn minimize(
+    specs: &[MatchSpec],
+    virtual_packages: &[GenericVirtualPackage],
+    deadline: Instant,
+    cancelled: &CancellationToken,
+    is_solvable: &mut impl FnMut(&[MatchSpec], &[GenericVirtualPackage], Duration) -> Option<bool>,
+) -> Option<MinimalConflict> {
+    if specs.len() + virtual_packages.len() > MAX_MINIMIZED_ITEMS {
+        return None;
+    }
+
+    // The time left for the next solve, if any
+    let remaining = || {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        (!remaining.is_zero() && !cancelled.is_cancelled()).then_some(remaining)
+    };
+    let mut complete = true;
+
+    let pick = |spec_indices: &[usize], virtual_indices: &[usize]| {
+        let specs: Vec<_> = spec_indices.iter().map(|&i| specs[i].clone()).collect();
+        let virtual_packages: Vec<_> = virtual_indices
+            .iter()
+            .map(|&i| virtual_packages[i].clone())
+            .collect();
+        (specs, virtual_packages)
+    };
+
+    let mut kept_specs: Vec<_> = (0..specs.len()).collect();
+    let mut kept_virtual: Vec<_> = (0..virtual_packages.len()).collect();
+
+    let mut i = 0;
+    while i < kept_specs.len() {
+        let Some(timeout) = remaining() else {
+            complete = false;
+            break;
+        };
+        let mut candidate = kept_specs.clone();
+        candidate.remove(i);
+        let (s, v) = pick(&candidate, &kept_virtual);
+        if is_solvable(&s, &v, timeout) == Some(false) {
+            kept_specs = candidate;
+        } else {
+            i += 1;
+        }
+    }
+
+    let mut i = 0;
+    while i < kept_virtual.len() {
+        let Some(timeout) = remaining() else {
+            complete = false;
+            break;
+        };
+        let mut candidate = kept_virtual.clone();
+        candidate.remove(i);
+        let (s, v) = pick(&kept_specs, &candidate);
+        if is_solvable(&s, &v, timeout) == Some(false) {
+            kept_virtual = candidate;
+        } else {
+            i += 1;
+        }
+    }
+
+    // Leaving out a spec of the minimal conflict resolves that conflict, but there may be others
+    let mut suggested_relaxation = None;
+    for &relaxed in &kept_specs {
+        let Some(timeout) = remaining() else {
+            complete = false;
+            break;
+        };
+        let others: Vec<_> = (0..specs.len()).filter(|&i| i != relaxed).collect();
+        let all_virtual: Vec<_> = (0..virtual_packages.len()).collect();
+        let (s, v) = pick(&others, &all_virtual);
+        if is_solvable(&s, &v, timeout) == Some(true) {
+            suggested_relaxation = Some(specs[relaxed].to_string());
+            break;
+        }
+    }
+
+    Some(MinimalConflict {
+        specs: kept_specs.iter().map(|&i| specs[i].to_string()).collect(),
+        virtual_packages: kept_virtual
+            .iter()
+            .map(|&i| {
+                let package = &virtual_packages[i];
+                format!(
+                    "{}={}={}",
+                    package.name.as_source(),
+                    package.version,
+                    package.build_string
+                )
+            })
+            .collect(),
+        complete,
+        suggested_relaxation,
+    })
+}
+
+/// Renders the conflicts as text meant for humans
+fn render(conflicts: &[Conflict]) -> String {
+    if conflicts.is_empty() {
+        return "The environment cannot be solved, see the solver messages for details".to_string();
+    }
+
+    let mut text = "The environment cannot be solved:".to_string();
+    for conflict in conflicts {
+        match conflict {
+            Conflict::Missing { requirement } => {
+                text.push_str(&format!("\n- nothing provides {requirement}"));
+            }
+            Conflict::Clash {
+                package,
+                requirements,
+            } => {
+                text.push_str(&format!("\n- no version of `{package}` satisfies all of:"));
+                for requirement in requirements {
+                    text.push_str(&format!("\n  - {requirement}"));
+                }
+            }
+        }
+    }
+
+    text
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rattler_conda_types::{Channel, ChannelConfig, PackageName, RepoData};
+    use serde_json::json;
+
+    fn records(packages: &[(&str, &str, &[&str])]) -> Vec<RepoDataRecord> {
+        let packages: serde_json::Map<_, _> = packages
+            .iter()
+            .map(|(name, version, depends)| {
+                let record = json!({
+                    "name": name,
+                    "version": version,
+                    "build": "0",
+                    "build_number": 0,
+                    "subdir": "linux-64",
+                    "depends": depends,
+                });
+                (format!("{name}-{version}-0.tar.bz2"), record)
+            })
+            .collect();
+
+        let repodata: RepoData = serde_json::from_value(json!({
+            "info": { "subdir": "linux-64" },
+            "packages": packages,
+            "packages.conda": {},
+            "repodata_version": 1,
+        }))
+        .unwrap();
+
+        let channel = Channel::from_str("conda-forge", &ChannelConfig::default()).unwrap();
+        repodata.into_repo_data_records(&channel)
+    }
+
+    fn specs(specs: &[&str]) -> Vec<MatchSpec> {
+        specs
+            .iter()
+            .map(|spec| MatchSpec::from_str(spec).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_missing_dependency() {
+        let records = records(&[("a", "1.0", &["b >=1"]), ("b", "0.5", &[])]);
+        let unsolvable = explain(&[records.iter().collect()], &specs(&["a"]), &[], Vec::new());
+
+        let [Conflict::Missing { requirement }] = unsolvable.conflicts.as_slice() else {
+            panic!("unexpected conflicts: {:?}", unsolvable.conflicts);
+        };
+        assert_eq!(requirement.spec, "b >=1");
+        assert_eq!(requirement.root_spec, "a");
+        assert_eq!(requirement.required_by, vec!["a=1.0=0"]);
+        assert_eq!(
+            unsolvable.explanation,
+            "The environment cannot be solved:\n- nothing provides `b >=1`, required by a=1.0=0 \
+             <- `a` (requested)"
+        );
+    }
+
+    #[test]
+    fn test_clashing_requirements() {
+        let records = records(&[
+            ("a", "1.0", &["c <2"]),
+            ("b", "1.0", &["c >=2"]),
+            ("c", "1.0", &[]),
+            ("c", "2.0", &[]),
+        ]);
+        let unsolvable = explain(
+            &[records.iter().collect()],
+            &specs(&["a", "b"]),
+            &[],
+            Vec::new(),
+        );
+
+        let [Conflict::Clash {
+            package,
+            requirements,
+        }] = unsolvable.conflicts.as_slice()
+        else {
+            panic!("unexpected conflicts: {:?}", unsolvable.conflicts);
+        };
+        assert_eq!(package, "c");
+        let specs: Vec<_> = requirements.iter().map(|r| r.spec.as_str()).collect();
+        assert_eq!(specs, vec!["c <2", "c >=2"]);
+        let roots: Vec<_> = requirements.iter().map(|r| r.root_spec.as_str()).collect();
+        assert_eq!(roots, vec!["a", "b"]);
+    }
+
+    #[test]
+    fn test_minimize() {
+        // `a` and `b` conflict, `c` is fine, and `d` only conflicts with `__cuda`
+        let conflicts = [("a", "b"), ("d", "__cuda")];
+        let mut is_solvable =
+            |specs: &[MatchSpec], virtual_packages: &[GenericVirtualPackage], _: Duration| {
+                let names: Vec<_> = specs
+                    .iter()
+                    .map(|spec| spec.name.as_ref().unwrap().as_normalized())
+                    .chain(virtual_packages.iter().map(|p| p.name.as_normalized()))
+                    .collect();
+                let conflicting = conflicts
+                    .iter()
+                    .any(|(x, y)| names.contains(x) && names.contains(y));
+                Some(!conflicting)
+            };
+
+        let virtual_packages = vec![GenericVirtualPackage {
+            name: PackageName::new_unchecked("__cuda"),
+            version: "11".parse().unwrap(),
+            build_string: "0".to_string(),
+        }];
+        let deadline = Instant::now() + Duration::from_secs(60);
+        let cancelled = CancellationToken::new();
+        let minimal = minimize(
+            &specs(&["a", "b", "c"]),
+            &virtual_packages,
+            deadline,
+            &cancelled,
+            &mut is_solvable,
+        )
+        .unwrap();
+
+        assert_eq!(minimal.specs, vec!["a", "b"]);
+        assert!(minimal.virtual_packages.is_empty());
+        assert_eq!(minimal.suggested_relaxation.as_deref(), Some("a"));
+        assert!(minimal.complete);
+
+        let minimal = minimize(
+            &specs(&["c", "d"]),
+            &virtual_packages,
+            deadline,
+            &cancelled,
+            &mut is_solvable,
+        )
+        .unwrap();
+        assert_eq!(minimal.specs, vec!["d"]);
+        assert_eq!(minimal.virtual_packages, vec!["__cuda=11=0"]);
+        assert_eq!(minimal.suggested_relaxation.as_deref(), Some("d"));
+
+        // Out of time, nothing is left out anymore
+        let minimal = minimize(
+            &specs(&["a", "b", "c"]),
+            &virtual_packages,
+            Instant::now(),
+            &cancelled,
+            &mut is_solvable,
+        )
+        .unwrap();
+        assert_eq!(minimal.specs, vec!["a", "b", "c"]);
+        assert_eq!(minimal.virtual_packages, vec!["__cuda=11=0"]);
+        assert_eq!(minimal.suggested_relaxation, None);
+        assert!(!minimal.complete);
+
+        // The same goes for cancelled requests
+        cancelled.cancel();
+        let minimal = minimize(
+            &specs(&["a", "b", "c"]),
+            &virtual_packages,
+            deadline,
+            &cancelled,
+            &mut is_solvable,
+        )
+        .unwrap();
+        assert_eq!(minimal.specs, vec!["a", "b", "c"]);
+        assert!(!minimal.complete);
+    }
+
+    #[test]
+    fn test_virtual_packages_satisfy_requirements() {
+        let records = records(&[("a", "1.0", &["__unix"])]);
+        let virtual_packages = vec![GenericVirtualPackage {
+            name: PackageName::new_unchecked("__unix"),
+            version: "0".parse().unwrap(),
+            build_string: "0".to_string(),
+        }];
+
+        let unsolvable = explain(
+            &[records.iter().collect()],
+            &specs(&["a"]),
+            &virtual_packages,
+            Vec::new(),
+        );
+        assert!(unsolvable.conflicts.is_empty());
+    }
+}
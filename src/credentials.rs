@@ -0,0 +1,62 @@
+//! Contains the middleware that authenticates repodata downloads from private channels
+
+use crate::config::Credentials;
+use arc_swap::ArcSwap;
+use reqwest::header::{HeaderValue, AUTHORIZATION};
+use reqwest::{Request, Response};
+use reqwest_middleware::{Middleware, Next};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use task_local_extensions::Extensions;
+
+/// Adds an `Authorization` header to requests sent to hosts that have credentials configured. The
+/// credentials are shared with the owner of the middleware, which can replace them at any time.
+pub struct CredentialsMiddleware {
+    credentials: Arc<ArcSwap<BTreeMap<String, Credentials>>>,
+}
+
+impl CredentialsMiddleware {
+    pub fn new(credentials: Arc<ArcSwap<BTreeMap<String, Credentials>>>) -> Self {
+        CredentialsMiddleware { credentials }
+    }
+}
+
+#[async_trait::async_trait]
+impl Middleware for CredentialsMiddleware {
+    async fn handle(
+        &self,
+        mut req: Request,
+        extensions: &mut Extensions,
+        next: Next<'_>,
+    ) -> reqwest_middleware::Result<Response> {
+        let all_credentials = self.credentials.load();
+        let credentials = req
+            .url()
+            .host_str()
+            .and_then(|host| all_credentials.get(host));
+
+        if let Some(credentials) = credentials {
+            if let Some(value) = header_value(credentials) {
+                req.headers_mut().insert(AUTHORIZATION, value);
+            }
+        }
+
+        next.run(req, extensions).await
+    }
+}
+
+fn header_value(credentials: &Credentials) -> Option<HeaderValue> {
+    let value = match credentials {
+        Credentials::BearerToken { token } => format!("Bearer {token}"),
+        Credentials::BasicHttp { username, password } => {
+            use base64::Engine;
+            let encoded =
+                base64::engine::general_purpose::STANDARD.encode(format!("{username}:{password}"));
+            format!("Basic {encoded}")
+        }
+    };
+
+    let mut value = HeaderValue::from_str(&value).ok()?;
+    value.set_sensitive(true);
+    Some(value)
+}
@@ -1,27 +1,168 @@
 //! Contains data transfer objects (DTOs) used as input and output of HTTP requests
 
+use crate::available_packages_cache::RepoDataInfo;
+use crate::cli::Solver;
+use crate::error::{ErrorCode, ERROR_SCHEMA_VERSION};
+use crate::solve::Exclusion;
+use chrono::{DateTime, Utc};
 use rattler_conda_types::RepoDataRecord;
 use serde::{Deserialize, Serialize};
 
 #[cfg_attr(test, derive(Serialize))]
-#[derive(Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize)]
 pub struct SolveEnvironment {
     pub name: Option<String>,
     pub platform: String,
     pub specs: Vec<String>,
     pub virtual_packages: Vec<String>,
-    pub channels: Vec<String>,
+    pub channels: Vec<ChannelRequest>,
+    /// When the environment is unsolvable, look for a minimal set of conflicting specs
+    #[serde(default)]
+    pub minimize_conflicts: bool,
+    /// Solve as if it were this moment (in RFC 3339 format), leaving out packages published later
+    #[serde(default)]
+    pub as_of: Option<String>,
+    /// Match specs of packages or builds that must not be used
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    #[serde(default)]
+    pub channel_priority: ChannelPriority,
+}
+
+/// How the order of the channels affects where packages come from, like conda's
+/// `channel_priority` setting
+#[cfg_attr(test, derive(Serialize))]
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum ChannelPriority {
+    /// A package is only taken from the first channel that has it, even if a later channel has a
+    /// version that would be preferred or is needed to solve the environment
+    Strict,
+    /// Like `Strict`, unless that makes the environment unsolvable, in which case it is solved
+    /// again with all channels
+    #[default]
+    Flexible,
+    /// All channels are available to the solver, regardless of their order
+    Disabled,
+}
+
+/// A channel in a request: either just its name or URL, or an object with options
+#[cfg_attr(test, derive(Serialize))]
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Hash)]
+#[serde(untagged)]
+pub enum ChannelRequest {
+    Name(String),
+    WithOptions {
+        name: String,
+        /// Solve without this channel if its repodata cannot be fetched
+        #[serde(default)]
+        optional: bool,
+    },
+}
+
+impl ChannelRequest {
+    pub fn name(&self) -> &str {
+        match self {
+            ChannelRequest::Name(name) | ChannelRequest::WithOptions { name, .. } => name,
+        }
+    }
+
+    pub fn optional(&self) -> bool {
+        match self {
+            ChannelRequest::Name(_) => false,
+            ChannelRequest::WithOptions { optional, .. } => *optional,
+        }
+    }
+}
+
+impl From<&str> for ChannelRequest {
+    fn from(name: &str) -> Self {
+        ChannelRequest::Name(name.to_string())
+    }
 }
 
 #[cfg_attr(test, derive(Deserialize))]
 #[derive(Serialize)]
 pub struct SolveEnvironmentOk {
     pub packages: Vec<RepoDataRecord>,
+    /// The candidates that were left out because of the server's deny-list or the request's
+    /// `exclude`
+    pub exclusions: Vec<Exclusion>,
+    /// The channel platforms that were left out of the solve because their repodata could not be
+    /// fetched
+    pub skipped_channels: Vec<SkippedChannel>,
+    pub metadata: SolveMetadata,
+}
+
+/// Describes how a solve came about, to tell why two solves of the same request differ
+#[cfg_attr(test, derive(Deserialize))]
+#[derive(Clone, Debug, Serialize)]
+pub struct SolveMetadata {
+    pub server_version: String,
+    pub solver: Solver,
+    /// How long fetching the repodata took, in milliseconds
+    pub fetch_ms: u64,
+    /// How long solving took, in milliseconds, not counting the wait for a solver thread. Zero if
+    /// the result came from the solve cache.
+    pub solve_ms: u64,
+    pub solve_cache_hit: bool,
+    /// Packages published after this moment were left out, because of `as_of` in the request or
+    /// the server's minimum package age
+    pub published_before: Option<DateTime<Utc>>,
+    /// The repodata the environment was solved against
+    pub repodata: Vec<RepoDataMetadata>,
 }
 
+#[cfg_attr(test, derive(Deserialize))]
+#[derive(Clone, Debug, Serialize)]
+pub struct RepoDataMetadata {
+    /// The channel as it appears in the request
+    pub channel: String,
+    pub platform: String,
+    /// Whether the repodata was already cached by the server
+    pub cache_hit: bool,
+    #[serde(flatten)]
+    pub info: RepoDataInfo,
+}
+
+#[cfg_attr(test, derive(Deserialize))]
+#[derive(Clone, Debug, Serialize)]
+pub struct SkippedChannel {
+    /// The channel as it appears in the request
+    pub channel: String,
+    pub platform: String,
+    /// Why the channel was skipped, using the code it would have failed the request with
+    pub code: ErrorCode,
+    pub reason: String,
+}
+
+/// The body of a successful `/admin/reload-config` response
+#[cfg_attr(test, derive(Deserialize))]
+#[derive(Debug, Serialize)]
+pub struct ReloadConfigOk {
+    /// The settings that changed but only take effect after a restart
+    pub restart_required: Vec<String>,
+}
+
+/// The body of all error responses, described by the schema at `/schema/errors`
 #[derive(Serialize)]
 pub struct SolveEnvironmentErr<T: Serialize> {
-    pub error_kind: String,
+    pub code: ErrorCode,
+    pub version: u32,
+    /// Deprecated in favor of `code`, and only kept until clients had a release to switch over
+    pub error_kind: &'static str,
     pub message: Option<String>,
     pub additional_info: Option<T>,
 }
+
+impl<T: Serialize> SolveEnvironmentErr<T> {
+    pub fn new(code: ErrorCode, message: Option<String>, additional_info: Option<T>) -> Self {
+        SolveEnvironmentErr {
+            code,
+            version: ERROR_SCHEMA_VERSION,
+            error_kind: code.legacy_kind(),
+            message,
+            additional_info,
+        }
+    }
+}
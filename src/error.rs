@@ -1,13 +1,17 @@
 //! Contains the errors that the API can return when trying to solve an environment
 
+use crate::auth::AuthError;
+use crate::conflicts::{self, Unsolvable};
 use crate::dto::SolveEnvironmentErr;
-use axum::http::StatusCode;
+use axum::http::{header, StatusCode};
 use axum::response::{IntoResponse, Response};
 use axum::Json;
 use rattler_repodata_gateway::fetch::FetchRepoDataError;
 use rattler_solve::SolveError;
 use reqwest::Url;
 use serde::{Serialize, Serializer};
+use serde_json::json;
+use std::time::Duration;
 use thiserror::Error;
 use tracing::{event, Level};
 
@@ -16,23 +20,86 @@ pub enum ApiError {
     #[error("internal error")]
     Internal(#[from] anyhow::Error),
     #[error("validation error: {0}")]
-    Validation(#[from] ValidationError),
+    Validation(#[from] ValidationErrors),
     #[error("error fetching repodata.json from {}", .0.to_string())]
     FetchRepoDataJson(Url, #[source] FetchRepoDataError),
+    #[error("invalid repodata.json from {}", .0.to_string())]
+    InvalidRepoData(Url, #[source] std::io::Error),
     #[error("solve error: {0}")]
     Solver(#[from] SolveError),
+    #[error("the environment cannot be solved")]
+    Unsolvable(Box<Unsolvable>),
+    #[error("the server is shutting down")]
+    ShuttingDown,
+    #[error("invalid configuration")]
+    InvalidConfig(#[source] anyhow::Error),
+    #[error("rate limit exceeded")]
+    RateLimited(Duration),
+    #[error("authentication failed: {0}")]
+    Unauthenticated(AuthError),
+    #[error("not allowed to use this endpoint")]
+    Forbidden,
+    #[error("too many solves queued")]
+    Overloaded,
 }
 
+impl ApiError {
+    /// The code and a description of the error, if it means a channel's repodata could not be
+    /// used. Other errors cannot be blamed on a single channel.
+    pub fn channel_failure(&self) -> Option<(ErrorCode, String)> {
+        match self {
+            ApiError::FetchRepoDataJson(_, e) => {
+                let (code, _) = classify_fetch_error(e);
+                Some((code, fetch_error_message(code).to_string()))
+            }
+            ApiError::InvalidRepoData(_, e) => Some((
+                ErrorCode::InvalidRepodata,
+                format!("unable to parse repodata.json: {e}"),
+            )),
+            _ => None,
+        }
+    }
+}
+
+/// A problem with one of the values in a request
 #[derive(Debug, Error)]
 pub enum ValidationError {
     #[error("invalid match specs")]
-    MatchSpecs(ParseErrors),
-    #[error("invalid virtual package")]
+    MatchSpec(ParseError),
+    #[error("invalid virtual packages")]
     VirtualPackage(ParseError),
     #[error("invalid channels")]
-    Channels(ParseErrors),
+    Channel(ParseError),
+    #[error("channels not allowed by the server")]
+    DisallowedChannel(ParseError),
     #[error("invalid platform")]
     Platform(ParseError),
+    #[error("invalid timestamp")]
+    Timestamp(ParseError),
+}
+
+impl ValidationError {
+    fn code(&self) -> ErrorCode {
+        match self {
+            ValidationError::MatchSpec(_) => ErrorCode::InvalidSpec,
+            ValidationError::VirtualPackage(_) => ErrorCode::InvalidVirtualPackage,
+            ValidationError::Channel(_) => ErrorCode::InvalidChannel,
+            ValidationError::DisallowedChannel(_) => ErrorCode::ChannelNotAllowed,
+            ValidationError::Platform(_) => ErrorCode::InvalidPlatform,
+            ValidationError::Timestamp(_) => ErrorCode::InvalidTimestamp,
+        }
+    }
+
+    fn parse_error(&self) -> &ParseError {
+        match self {
+            ValidationError::MatchSpec(error)
+            | ValidationError::VirtualPackage(error)
+            | ValidationError::Channel(error)
+            | ValidationError::DisallowedChannel(error)
+            | ValidationError::Platform(error)
+            | ValidationError::Timestamp(error) => error,
+        }
+    }
 }
 
 impl Serialize for ValidationError {
@@ -40,25 +107,380 @@ impl Serialize for ValidationError {
     where
         S: Serializer,
     {
-        match self {
-            ValidationError::MatchSpecs(errors) | ValidationError::Channels(errors) => {
-                errors.serialize(serializer)
-            }
-            ValidationError::VirtualPackage(error) | ValidationError::Platform(error) => {
-                error.serialize(serializer)
+        #[derive(Serialize)]
+        struct Entry<'a> {
+            code: ErrorCode,
+            #[serde(flatten)]
+            error: &'a ParseError,
+        }
+
+        Entry {
+            code: self.code(),
+            error: self.parse_error(),
+        }
+        .serialize(serializer)
+    }
+}
+
+/// All problems found in a request, in the order of its fields. Serializes to a list of errors.
+#[derive(Debug, Error, Serialize)]
+#[error("{}", self.description())]
+pub struct ValidationErrors(pub Vec<ValidationError>);
+
+impl ValidationErrors {
+    /// The code of the response, which is the one of the first error
+    fn code(&self) -> ErrorCode {
+        self.0
+            .first()
+            .map(ValidationError::code)
+            .unwrap_or(ErrorCode::Internal)
+    }
+
+    /// Describes each kind of error once, e.g. `invalid match specs, invalid platform`
+    fn description(&self) -> String {
+        let mut descriptions: Vec<String> = Vec::new();
+        for error in &self.0 {
+            let description = error.to_string();
+            if !descriptions.contains(&description) {
+                descriptions.push(description);
             }
         }
+        descriptions.join(", ")
     }
 }
 
 #[derive(Debug, Serialize)]
 pub struct ParseError {
+    /// Where the value is in the request, e.g. `specs[2]` or `platform`
+    pub field: String,
     pub input: String,
     pub error: String,
 }
 
-#[derive(Debug, Serialize)]
-pub struct ParseErrors(pub Vec<ParseError>);
+/// The version of the error responses. It is increased whenever an error code changes meaning or
+/// the shape of its `additional_info` changes. Adding codes does not change the version.
+pub const ERROR_SCHEMA_VERSION: u32 = 1;
+
+/// Stable, machine-readable identifiers of the errors returned by the API
+#[cfg_attr(test, derive(serde::Deserialize))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ErrorCode {
+    /// The solver did not finish in time. No `additional_info`.
+    Timeout,
+    /// The environment cannot be solved. See [`Unsolvable`] for the `additional_info`.
+    Unsolvable,
+    /// A channel or one of its platforms does not exist. `additional_info` has the `url`.
+    ChannelNotFound,
+    /// The server is not allowed to access a channel. `additional_info` has the `url`.
+    ChannelUnauthorized,
+    /// A channel could not be reached. `additional_info` has the `url`.
+    ChannelUnavailable,
+    /// A channel did not respond in time. `additional_info` has the `url`.
+    ChannelTimeout,
+    /// A channel returned repodata that could not be parsed. `additional_info` has the `url`.
+    InvalidRepodata,
+    /// A channel is not allowed by the server. This and the other validation errors below have
+    /// all problems of the request as `additional_info`, each with its own `code` and `field`.
+    /// The code of the response is the one of the first problem.
+    ChannelNotAllowed,
+    /// A spec could not be parsed
+    InvalidSpec,
+    /// A virtual package could not be parsed
+    InvalidVirtualPackage,
+    /// A channel could not be parsed
+    InvalidChannel,
+    /// The platform is unknown
+    InvalidPlatform,
+    /// A timestamp is not in RFC 3339 format
+    InvalidTimestamp,
+    /// `additional_info` has the `retry_after_seconds`, like the `Retry-After` header
+    RateLimited,
+    /// The server has too many solves queued. `additional_info` has the `retry_after_seconds`.
+    Overloaded,
+    /// The request lacks valid credentials. No `additional_info`.
+    Unauthenticated,
+    /// The client is not allowed to use the endpoint. No `additional_info`.
+    Forbidden,
+    /// The server is shutting down. No `additional_info`.
+    ShuttingDown,
+    /// Reloading the configuration failed. No `additional_info`, the message has the details.
+    InvalidConfig,
+    /// Anything else, which is logged by the server. No `additional_info`.
+    Internal,
+}
+
+impl ErrorCode {
+    pub const ALL: [ErrorCode; 20] = [
+        ErrorCode::Timeout,
+        ErrorCode::Unsolvable,
+        ErrorCode::ChannelNotFound,
+        ErrorCode::ChannelUnauthorized,
+        ErrorCode::ChannelUnavailable,
+        ErrorCode::ChannelTimeout,
+        ErrorCode::InvalidRepodata,
+        ErrorCode::ChannelNotAllowed,
+        ErrorCode::InvalidSpec,
+        ErrorCode::InvalidVirtualPackage,
+        ErrorCode::InvalidChannel,
+        ErrorCode::InvalidPlatform,
+        ErrorCode::InvalidTimestamp,
+        ErrorCode::RateLimited,
+        ErrorCode::Overloaded,
+        ErrorCode::Unauthenticated,
+        ErrorCode::Forbidden,
+        ErrorCode::ShuttingDown,
+        ErrorCode::InvalidConfig,
+        ErrorCode::Internal,
+    ];
+
+    /// The JSON Schema of the `additional_info` that comes with this code
+    fn additional_info_schema(self) -> serde_json::Value {
+        let url = json!({
+            "type": "object",
+            "properties": { "url": { "type": "string", "format": "uri" } },
+            "required": ["url"]
+        });
+        let retry_after = json!({
+            "type": "object",
+            "properties": { "retry_after_seconds": { "type": "integer", "minimum": 1 } },
+            "required": ["retry_after_seconds"]
+        });
+        let parse_errors = json!({
+            "type": "array",
+            "items": {
+                "type": "object",
+                "properties": {
+                    "code": { "type": "string" },
+                    "field": { "type": "string" },
+                    "input": { "type": "string" },
+                    "error": { "type": "string" }
+                },
+                "required": ["code", "field", "input", "error"]
+            }
+        });
+        let requirement = json!({
+            "type": "object",
+            "properties": {
+                "spec": { "type": "string" },
+                "root_spec": { "type": "string" },
+                "required_by": { "type": "array", "items": { "type": "string" } }
+            },
+            "required": ["spec", "root_spec", "required_by"]
+        });
+        let strings = json!({ "type": "array", "items": { "type": "string" } });
+        let exclusions = json!({
+            "type": "array",
+            "items": {
+                "type": "object",
+                "properties": {
+                    "spec": { "type": "string" },
+                    "source": { "enum": ["policy", "request", "missing_timestamp"] },
+                    "count": { "type": "integer" },
+                    "records": strings
+                },
+                "required": ["spec", "source", "count", "records"]
+            }
+        });
+
+        match self {
+            ErrorCode::ChannelNotFound
+            | ErrorCode::ChannelUnauthorized
+            | ErrorCode::ChannelUnavailable
+            | ErrorCode::ChannelTimeout
+            | ErrorCode::InvalidRepodata => url,
+            ErrorCode::RateLimited | ErrorCode::Overloaded => retry_after,
+            ErrorCode::ChannelNotAllowed
+            | ErrorCode::InvalidSpec
+            | ErrorCode::InvalidVirtualPackage
+            | ErrorCode::InvalidChannel
+            | ErrorCode::InvalidPlatform
+            | ErrorCode::InvalidTimestamp => parse_errors,
+            ErrorCode::Unsolvable => json!({
+                "type": "object",
+                "properties": {
+                    "conflicts": {
+                        "type": "array",
+                        "items": {
+                            "oneOf": [
+                                {
+                                    "type": "object",
+                                    "properties": {
+                                        "kind": { "const": "missing" },
+                                        "requirement": requirement
+                                    },
+                                    "required": ["kind", "requirement"]
+                                },
+                                {
+                                    "type": "object",
+                                    "properties": {
+                                        "kind": { "const": "clash" },
+                                        "package": { "type": "string" },
+                                        "requirements": { "type": "array", "items": requirement }
+                                    },
+                                    "required": ["kind", "package", "requirements"]
+                                }
+                            ]
+                        }
+                    },
+                    "explanation": { "type": "string" },
+                    "solver_messages": strings,
+                    "exclusions": exclusions,
+                    "minimal_conflict": {
+                        "type": "object",
+                        "properties": {
+                            "specs": strings,
+                            "virtual_packages": strings,
+                            "complete": { "type": "boolean" },
+                            "suggested_relaxation": { "type": ["string", "null"] }
+                        },
+                        "required": ["specs", "virtual_packages", "complete", "suggested_relaxation"]
+                    }
+                },
+                "required": ["conflicts", "explanation", "solver_messages"]
+            }),
+            ErrorCode::Timeout
+            | ErrorCode::Unauthenticated
+            | ErrorCode::Forbidden
+            | ErrorCode::ShuttingDown
+            | ErrorCode::InvalidConfig
+            | ErrorCode::Internal => json!({ "type": "null" }),
+        }
+    }
+
+    /// The `error_kind` that responses had before they got a `code`, which is still sent for
+    /// clients that were not updated yet
+    pub fn legacy_kind(self) -> &'static str {
+        match self {
+            ErrorCode::Unsolvable => "solver",
+            ErrorCode::ChannelNotFound
+            | ErrorCode::ChannelUnauthorized
+            | ErrorCode::ChannelUnavailable
+            | ErrorCode::ChannelTimeout
+            | ErrorCode::InvalidRepodata => "http",
+            // Solver timeouts used to be reported as validation errors
+            ErrorCode::Timeout
+            | ErrorCode::ChannelNotAllowed
+            | ErrorCode::InvalidSpec
+            | ErrorCode::InvalidVirtualPackage
+            | ErrorCode::InvalidChannel
+            | ErrorCode::InvalidPlatform
+            | ErrorCode::InvalidTimestamp => "validation",
+            ErrorCode::RateLimited => "rate_limit",
+            ErrorCode::Overloaded => "overloaded",
+            ErrorCode::Unauthenticated => "unauthenticated",
+            ErrorCode::Forbidden => "forbidden",
+            ErrorCode::ShuttingDown => "unavailable",
+            ErrorCode::InvalidConfig => "config",
+            ErrorCode::Internal => "internal",
+        }
+    }
+}
+
+/// The JSON Schema of the error responses, with one alternative per error code
+pub fn error_schema() -> serde_json::Value {
+    let alternatives: Vec<_> = ErrorCode::ALL
+        .iter()
+        .map(|&code| {
+            json!({
+                "type": "object",
+                "properties": {
+                    "code": { "const": code },
+                    "version": { "const": ERROR_SCHEMA_VERSION },
+                    "error_kind": { "const": code.legacy_kind(), "deprecated": true },
+                    "message": { "type": ["string", "null"] },
+                    "additional_info": code.additional_info_schema()
+                },
+                "required": ["code", "version", "message", "additional_info"]
+            })
+        })
+        .collect();
+
+    json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": "rattler-server error response",
+        "oneOf": alternatives
+    })
+}
+
+/// The `additional_info` of errors about a channel
+#[derive(Serialize)]
+struct ChannelErrorInfo {
+    url: String,
+}
+
+/// The `additional_info` of errors that tell the client when to retry
+#[derive(Serialize)]
+struct RetryInfo {
+    retry_after_seconds: u64,
+}
+
+/// Determines why a channel could not be fetched. Only a missing channel or platform is the
+/// client's mistake (400), anything else is a problem upstream: the channel refused the server's
+/// credentials or was unreachable (502), or it took too long to respond (504).
+fn classify_fetch_error(error: &FetchRepoDataError) -> (ErrorCode, StatusCode) {
+    let code = match error {
+        FetchRepoDataError::NotFound(_) => ErrorCode::ChannelNotFound,
+        _ => error_chain(error)
+            .find_map(classify_source)
+            .unwrap_or(ErrorCode::ChannelUnavailable),
+    };
+
+    let status = match code {
+        ErrorCode::ChannelNotFound => StatusCode::BAD_REQUEST,
+        ErrorCode::ChannelTimeout => StatusCode::GATEWAY_TIMEOUT,
+        _ => StatusCode::BAD_GATEWAY,
+    };
+    (code, status)
+}
+
+fn fetch_error_message(code: ErrorCode) -> &'static str {
+    match code {
+        ErrorCode::ChannelNotFound => "the channel or platform does not exist",
+        ErrorCode::ChannelUnauthorized => "the channel denied access to repodata.json",
+        ErrorCode::ChannelTimeout => "timed out retrieving repodata.json",
+        _ => "unable to retrieve repodata.json",
+    }
+}
+
+/// Recognizes the errors of the HTTP client, which are buried in the fetch error
+fn classify_source(error: &(dyn std::error::Error + 'static)) -> Option<ErrorCode> {
+    if let Some(reqwest_middleware::Error::Reqwest(e)) =
+        error.downcast_ref::<reqwest_middleware::Error>()
+    {
+        return classify_reqwest_error(e);
+    }
+    if let Some(e) = error.downcast_ref::<reqwest::Error>() {
+        return classify_reqwest_error(e);
+    }
+    if let Some(e) = error.downcast_ref::<std::io::Error>() {
+        if e.kind() == std::io::ErrorKind::TimedOut {
+            return Some(ErrorCode::ChannelTimeout);
+        }
+        // The error wrapped by an `io::Error` is not its source, so it is checked here
+        return e.get_ref().and_then(|inner| classify_source(inner));
+    }
+    None
+}
+
+fn classify_reqwest_error(error: &reqwest::Error) -> Option<ErrorCode> {
+    if error.is_timeout() {
+        return Some(ErrorCode::ChannelTimeout);
+    }
+    // `reqwest` has its own `StatusCode` type, so compare the numbers
+    match error.status()?.as_u16() {
+        404 => Some(ErrorCode::ChannelNotFound),
+        401 | 403 => Some(ErrorCode::ChannelUnauthorized),
+        _ => None,
+    }
+}
+
+/// Iterates over the error and its sources
+fn error_chain<'a>(
+    error: &'a (dyn std::error::Error + 'static),
+) -> impl Iterator<Item = &'a (dyn std::error::Error + 'static)> {
+    std::iter::successors(Some(error), |e| e.source())
+}
 
 fn rewrite_error(api_error: ApiError) -> ApiError {
     match api_error {
@@ -83,11 +505,11 @@ pub fn response_from_error(api_error: ApiError) -> Response {
             );
             (
                 StatusCode::INTERNAL_SERVER_ERROR,
-                Json(SolveEnvironmentErr::<()> {
-                    error_kind: "internal".to_string(),
-                    message: None,
-                    additional_info: None,
-                }),
+                Json(SolveEnvironmentErr::<()>::new(
+                    ErrorCode::Internal,
+                    None,
+                    None,
+                )),
             )
                 .into_response()
         }
@@ -97,51 +519,152 @@ pub fn response_from_error(api_error: ApiError) -> Response {
                 "Error fetching repodata.json: {}",
                 e.to_string()
             );
+            let (code, status) = classify_fetch_error(&e);
             (
-                StatusCode::BAD_REQUEST,
-                Json(SolveEnvironmentErr {
-                    error_kind: "http".to_string(),
-                    message: Some("unable to retrieve repodata.json".to_string()),
-                    additional_info: Some(format!("url: {url}")),
-                }),
+                status,
+                Json(SolveEnvironmentErr::new(
+                    code,
+                    Some(fetch_error_message(code).to_string()),
+                    Some(ChannelErrorInfo {
+                        url: url.to_string(),
+                    }),
+                )),
+            )
+                .into_response()
+        }
+        ApiError::InvalidRepoData(url, e) => {
+            event!(Level::WARN, "Invalid repodata.json from {url}: {e}");
+            (
+                StatusCode::BAD_GATEWAY,
+                Json(SolveEnvironmentErr::new(
+                    ErrorCode::InvalidRepodata,
+                    Some(format!("unable to parse repodata.json: {e}")),
+                    Some(ChannelErrorInfo {
+                        url: url.to_string(),
+                    }),
+                )),
             )
                 .into_response()
         }
         ApiError::Validation(e) => (
             StatusCode::BAD_REQUEST,
-            Json(SolveEnvironmentErr {
-                error_kind: "validation".to_string(),
-                message: Some(e.to_string()),
-                additional_info: Some(e),
-            }),
+            Json(SolveEnvironmentErr::new(
+                e.code(),
+                Some(e.to_string()),
+                Some(e),
+            )),
         )
             .into_response(),
-        ApiError::Solver(SolveError::UnsupportedOperations(_)) => unreachable!(),
-        ApiError::Solver(SolveError::Unsolvable(e)) => (
-            StatusCode::CONFLICT,
-            Json(SolveEnvironmentErr {
-                error_kind: "solver".to_string(),
-                message: Some("no solution found for the specified dependencies".to_string()),
-                additional_info: Some(e),
-            }),
+        ApiError::ShuttingDown => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(SolveEnvironmentErr::<()>::new(
+                ErrorCode::ShuttingDown,
+                Some("the server is shutting down".to_string()),
+                None,
+            )),
         )
             .into_response(),
-        ApiError::Solver(SolveError::ParseMatchSpecError(e)) => (
-            StatusCode::BAD_REQUEST,
-            Json(SolveEnvironmentErr {
-                error_kind: "validation".to_string(),
-                message: Some("invalid match spec".to_string()),
-                additional_info: Some(e.to_string()),
-            }),
+        ApiError::InvalidConfig(e) => {
+            event!(Level::ERROR, "Invalid configuration: {e:#}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(SolveEnvironmentErr::<()>::new(
+                    ErrorCode::InvalidConfig,
+                    Some(format!("{e:#}")),
+                    None,
+                )),
+            )
+                .into_response()
+        }
+        ApiError::RateLimited(retry_after) => {
+            // Round up, so clients that respect the header don't retry too early
+            let retry_after_seconds =
+                (retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0)).max(1);
+            (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(header::RETRY_AFTER, retry_after_seconds.to_string())],
+                Json(SolveEnvironmentErr::new(
+                    ErrorCode::RateLimited,
+                    Some(format!(
+                        "too many requests, retry after {retry_after_seconds} seconds"
+                    )),
+                    Some(RetryInfo {
+                        retry_after_seconds,
+                    }),
+                )),
+            )
+                .into_response()
+        }
+        ApiError::Unauthenticated(e) => (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, "Bearer")],
+            Json(SolveEnvironmentErr::<()>::new(
+                ErrorCode::Unauthenticated,
+                Some(e.to_string()),
+                None,
+            )),
+        )
+            .into_response(),
+        ApiError::Forbidden => (
+            StatusCode::FORBIDDEN,
+            Json(SolveEnvironmentErr::<()>::new(
+                ErrorCode::Forbidden,
+                Some("not allowed to use this endpoint".to_string()),
+                None,
+            )),
+        )
+            .into_response(),
+        ApiError::Overloaded => {
+            event!(Level::WARN, "Solver queue is full, rejecting request");
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                [(header::RETRY_AFTER, "1")],
+                Json(SolveEnvironmentErr::new(
+                    ErrorCode::Overloaded,
+                    Some("the server is busy with other solves, try again later".to_string()),
+                    Some(RetryInfo {
+                        retry_after_seconds: 1,
+                    }),
+                )),
+            )
+                .into_response()
+        }
+        ApiError::Unsolvable(e) => (
+            StatusCode::CONFLICT,
+            Json(SolveEnvironmentErr::new(
+                ErrorCode::Unsolvable,
+                Some("no solution found for the specified dependencies".to_string()),
+                Some(e),
+            )),
         )
             .into_response(),
+        ApiError::Solver(SolveError::UnsupportedOperations(_)) => unreachable!(),
+        ApiError::Solver(SolveError::Unsolvable(messages)) => {
+            // Without the records there is nothing to explain, but the shape stays the same
+            let unsolvable = conflicts::explain(&[], &[], &[], messages);
+            response_from_error(ApiError::Unsolvable(Box::new(unsolvable)))
+        }
+        ApiError::Solver(SolveError::ParseMatchSpecError(e)) => {
+            // The specs of the request are parsed before solving, so this comes from repodata
+            event!(Level::ERROR, "Invalid dependency in repodata: {e}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(SolveEnvironmentErr::<()>::new(
+                    ErrorCode::Internal,
+                    Some("invalid dependency in repodata".to_string()),
+                    None,
+                )),
+            )
+                .into_response()
+        }
+        // Like a channel that does not respond in time, nothing is wrong with the request itself
         ApiError::Solver(SolveError::Cancelled) => (
-            StatusCode::BAD_REQUEST,
-            Json(SolveEnvironmentErr::<String> {
-                error_kind: "validation".to_string(),
-                message: Some("solver process cancelled".to_string()),
-                additional_info: None,
-            }),
+            StatusCode::GATEWAY_TIMEOUT,
+            Json(SolveEnvironmentErr::<()>::new(
+                ErrorCode::Timeout,
+                Some("the solver did not finish in time".to_string()),
+                None,
+            )),
         )
             .into_response(),
     }
@@ -154,3 +677,33 @@ async fn test_unsupported_operations_is_mapped_to_response() {
 
     assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
 }
+
+#[tokio::test]
+async fn test_cancelled_solve_is_a_timeout() {
+    let response = response_from_error(ApiError::Solver(SolveError::Cancelled));
+    assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["code"], "timeout");
+    assert_eq!(body["version"], ERROR_SCHEMA_VERSION);
+    assert_eq!(body["error_kind"], "validation");
+}
+
+#[test]
+fn test_error_schema_lists_all_codes() {
+    let schema = error_schema();
+    let codes: Vec<_> = schema["oneOf"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|alternative| alternative["properties"]["code"]["const"].clone())
+        .collect();
+
+    assert_eq!(codes.len(), ErrorCode::ALL.len());
+    for code in ErrorCode::ALL {
+        assert!(codes.contains(&serde_json::to_value(code).unwrap()));
+    }
+}
@@ -3,7 +3,7 @@ use std::hash::Hash;
 
 use dashmap::mapref::entry::Entry;
 use dashmap::DashMap;
-use std::sync::Arc;
+use std::sync::{Arc, RwLock as SyncRwLock};
 use std::time::Duration;
 use tokio::sync::{OwnedRwLockWriteGuard, RwLock};
 use tracing::{event, Level};
@@ -15,9 +15,12 @@ use mock_instant::Instant;
 use std::time::Instant;
 
 pub struct GenericCache<TKey, TValue> {
-    cached_data: DashMap<TKey, (Arc<TValue>, Instant)>,
-    active_writes: DashMap<TKey, Arc<RwLock<()>>>,
-    expiration: Duration,
+    /// The cached values, together with the instant they were inserted and their expiration
+    cached_data: DashMap<TKey, (Arc<TValue>, Instant, Duration)>,
+    /// Shared with the write tokens, which remove their entry when they are dropped
+    active_writes: Arc<DashMap<TKey, Arc<RwLock<()>>>>,
+    /// The expiration of values inserted through [`GenericCache::set`]
+    expiration: SyncRwLock<Duration>,
 }
 
 impl<TKey: Hash + Eq + Display + Clone, TValue> GenericCache<TKey, TValue> {
@@ -25,18 +28,23 @@ impl<TKey: Hash + Eq + Display + Clone, TValue> GenericCache<TKey, TValue> {
     pub fn with_expiration(expiration: Duration) -> GenericCache<TKey, TValue> {
         GenericCache {
             cached_data: DashMap::new(),
-            active_writes: DashMap::new(),
-            expiration,
+            active_writes: Arc::new(DashMap::new()),
+            expiration: SyncRwLock::new(expiration),
         }
     }
 
+    /// Changes the default expiration. Values that are already cached keep their expiration.
+    pub fn set_expiration(&self, expiration: Duration) {
+        *self.expiration.write().unwrap() = expiration;
+    }
+
     /// Removes outdated data from the cache
     pub fn gc(&self) {
         let mut expired_keys = Vec::new();
         for item in &self.cached_data {
             let key = item.key();
-            let (_value, insert_instant) = item.value();
-            if Instant::now() > *insert_instant + self.expiration {
+            let (_value, insert_instant, expiration) = item.value();
+            if Instant::now() > *insert_instant + *expiration {
                 event!(Level::TRACE, "Key marked for GC: {key}");
 
                 // We remove the keys in a separate step to avoid deadlocks
@@ -61,7 +69,8 @@ impl<TKey: Hash + Eq + Display + Clone, TValue> GenericCache<TKey, TValue> {
     pub async fn get_cached(&self, key: &TKey) -> GetCachedResult<TKey, TValue> {
         loop {
             if let Some(repodata) = self.cached_data.get(key) {
-                if Instant::now() > repodata.value().1 + self.expiration {
+                let (_, insert_instant, expiration) = repodata.value();
+                if Instant::now() > *insert_instant + *expiration {
                     event!(Level::TRACE, "Cache hit, but data was stale: {key}");
                 } else {
                     event!(Level::TRACE, "Cache hit: {key}");
@@ -72,13 +81,28 @@ impl<TKey: Hash + Eq + Display + Clone, TValue> GenericCache<TKey, TValue> {
             // Cache miss
             match self.active_writes.entry(key.clone()) {
                 Entry::Occupied(e) => {
+                    let lock = e.get().clone();
+                    if let Ok(write_guard) = lock.clone().try_write_owned() {
+                        // Nobody holds the write lock, yet the value was never set, so the previous
+                        // writer gave up (e.g. because its download failed or its request was
+                        // dropped). Take over instead of waiting forever.
+                        event!(Level::TRACE, "Previous write was abandoned: {key}");
+                        return GetCachedResult::NotFound(WriteToken {
+                            key: key.clone(),
+                            active_writes: self.active_writes.clone(),
+                            _rw_guard: write_guard,
+                        });
+                    }
+
                     // A download is going on. Wait for it to finish and try to get the result in
-                    // the next loop iteration
+                    // the next loop iteration. The map entry is released first, so other keys
+                    // are not blocked in the meantime.
+                    drop(e);
                     event!(
                         Level::TRACE,
                         "Download already started, waiting for it to finish..."
                     );
-                    let _ = e.get().read().await;
+                    let _ = lock.read().await;
                 }
                 Entry::Vacant(e) => {
                     // No download is going on, register ours so others can see it (there can still
@@ -89,28 +113,58 @@ impl<TKey: Hash + Eq + Display + Clone, TValue> GenericCache<TKey, TValue> {
                     e.insert(lock);
                     return GetCachedResult::NotFound(WriteToken {
                         key: key.clone(),
-                        rw_guard: write_guard,
+                        active_writes: self.active_writes.clone(),
+                        _rw_guard: write_guard,
                     });
                 }
             };
         }
     }
 
+    /// Waits until the writes that are currently in progress have finished
+    pub async fn wait_for_active_writes(&self) {
+        // Clone the locks first, to avoid holding references into the map while awaiting
+        let locks: Vec<_> = self
+            .active_writes
+            .iter()
+            .map(|item| item.value().clone())
+            .collect();
+
+        for lock in locks {
+            let _ = lock.read().await;
+        }
+    }
+
+    /// The amount of writes that are currently in progress
+    #[cfg(test)]
+    pub fn active_write_count(&self) -> usize {
+        self.active_writes.len()
+    }
+
     /// Caches the value at the given key and notifies
     pub fn set(&self, token: WriteToken<TKey>, value: Arc<TValue>) {
+        let expiration = *self.expiration.read().unwrap();
+        self.set_with_expiration(token, value, expiration);
+    }
+
+    /// Like [`GenericCache::set`], but the value expires after `expiration` instead of the cache's
+    /// default expiration
+    pub fn set_with_expiration(
+        &self,
+        token: WriteToken<TKey>,
+        value: Arc<TValue>,
+        expiration: Duration,
+    ) {
         self.cached_data
-            .insert(token.key.clone(), (value, Instant::now()));
+            .insert(token.key.clone(), (value, Instant::now(), expiration));
 
         // This will notify anyone who is waiting for the write to finish
-        drop(token.rw_guard);
-
-        // Remove the active write, since it is no longer necessary
-        self.active_writes.remove(&token.key);
+        drop(token);
     }
 }
 
 /// Represents the result of a call to [`GenericCache::get_cached`]
-pub enum GetCachedResult<TKey, TValue> {
+pub enum GetCachedResult<TKey: Hash + Eq, TValue> {
     /// The key was found in the cache and its value is included in the enum variant
     Found(Arc<TValue>),
     /// The key was not found in the cache and there are no active writes, so the caller is expected
@@ -119,10 +173,21 @@ pub enum GetCachedResult<TKey, TValue> {
     NotFound(WriteToken<TKey>),
 }
 
-/// A token that must be used when adding values to the cache
-pub struct WriteToken<T> {
+/// A token that must be used when adding values to the cache. Dropping it without setting a value
+/// abandons the write, e.g. when the value could not be retrieved.
+pub struct WriteToken<T: Hash + Eq> {
     key: T,
-    rw_guard: OwnedRwLockWriteGuard<()>,
+    active_writes: Arc<DashMap<T, Arc<RwLock<()>>>>,
+    /// Released after the active write is removed, which notifies anyone waiting for the write
+    _rw_guard: OwnedRwLockWriteGuard<()>,
+}
+
+impl<T: Hash + Eq> Drop for WriteToken<T> {
+    fn drop(&mut self) {
+        // Removed while the write lock is still held, so the waiters woken up by its release either
+        // find the value or become the next writer
+        self.active_writes.remove(&self.key);
+    }
 }
 
 #[cfg(test)]
@@ -186,6 +251,65 @@ mod test {
         assert_eq!(*get_cached_2.await.unwrap(), "foo");
     }
 
+    #[tokio::test]
+    async fn test_abandoned_write_is_taken_over() {
+        let cache = default_cache();
+
+        // The writer goes away without setting a value
+        let write_token = get_cached_not_found(&cache, 42).await;
+        drop(write_token);
+
+        // The next caller becomes the writer instead of waiting forever
+        let write_token = get_cached_not_found(&cache, 42).await;
+        cache.set(write_token, Arc::new("foo"));
+        assert!(matches!(
+            cache.get_cached(&42).await,
+            GetCachedResult::Found(value) if *value == "foo"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_abandoned_write_is_forgotten() {
+        let cache = default_cache();
+
+        let write_token = get_cached_not_found(&cache, 42).await;
+        assert_eq!(cache.active_write_count(), 1);
+
+        drop(write_token);
+        assert_eq!(cache.active_write_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_active_writes() {
+        let cache = Arc::new(default_cache());
+
+        let write_token = get_cached_not_found(&cache, 42).await;
+
+        let cloned_cache = cache.clone();
+        let waiter = tokio::spawn(async move { cloned_cache.wait_for_active_writes().await });
+
+        // The write is still in progress
+        tokio::task::yield_now().await;
+        assert!(!waiter.is_finished());
+
+        cache.set(write_token, Arc::new("foo"));
+        waiter.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_gc_respects_per_item_expiration() {
+        let cache = default_cache();
+        add_item(&cache, 42, "foo").await;
+        let write_token = get_cached_not_found(&cache, 43).await;
+        cache.set_with_expiration(write_token, Arc::new("bar"), Duration::from_secs(10));
+
+        // The item with the short expiration is collected, the other one is not
+        MockClock::advance(Duration::from_secs(20));
+        cache.gc();
+        assert_eq!(cache.cached_data.len(), 1);
+        assert!(cache.cached_data.contains_key(&42));
+    }
+
     async fn get_cached_not_found(
         cache: &GenericCache<usize, &'static str>,
         key: usize,
@@ -0,0 +1,151 @@
+//! Contains the bookkeeping used to run identical work only once when it is requested concurrently
+
+use futures::future::{BoxFuture, Shared, WeakShared};
+use futures::FutureExt;
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::Hash;
+use std::sync::Mutex;
+
+/// Keeps track of the work that is currently running, so callers asking for the same key share the
+/// result instead of repeating the work. This is the same idea as [`crate::generic_cache`], but
+/// nothing is kept around once the work is finished.
+///
+/// The work is driven by its callers: once all of them are gone, it is dropped.
+pub struct InFlight<K, V> {
+    pending: Mutex<HashMap<K, WeakShared<BoxFuture<'static, V>>>>,
+}
+
+impl<K, V> Default for InFlight<K, V> {
+    fn default() -> Self {
+        InFlight {
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone, V: Clone + Send + Sync + 'static> InFlight<K, V> {
+    /// Waits for the result of the work registered for `key`, starting it through `work` if there
+    /// is none. The returned flag is true if the result was shared with an earlier caller.
+    pub async fn run<F>(&self, key: K, work: impl FnOnce() -> F) -> (V, bool)
+    where
+        F: Future<Output = V> + Send + 'static,
+    {
+        let (shared, joined) = {
+            let mut pending = self.pending.lock().unwrap();
+            match pending.get(&key).and_then(|weak| weak.upgrade()) {
+                // Finished work is about to be removed, its result should not be handed out again
+                Some(shared) if shared.peek().is_none() => (shared, true),
+                _ => {
+                    let shared = work().boxed().shared();
+                    pending.insert(key.clone(), shared.downgrade().unwrap());
+                    (shared, false)
+                }
+            }
+        };
+
+        let mut waiter = Waiter {
+            in_flight: self,
+            key,
+            shared: Some(shared),
+        };
+        let result = waiter.shared.as_mut().unwrap().await;
+        (result, joined)
+    }
+}
+
+/// Removes the work from the map once it has finished or has no callers left, including when the
+/// caller is dropped halfway
+struct Waiter<'a, K: Hash + Eq, V> {
+    in_flight: &'a InFlight<K, V>,
+    key: K,
+    shared: Option<Shared<BoxFuture<'static, V>>>,
+}
+
+impl<K: Hash + Eq, V> Drop for Waiter<'_, K, V> {
+    fn drop(&mut self) {
+        // Our handle must be gone before checking whether anybody else is still waiting
+        drop(self.shared.take());
+
+        let mut pending = self.in_flight.pending.lock().unwrap();
+        let done = match pending.get(&self.key).and_then(|weak| weak.upgrade()) {
+            Some(shared) => shared.peek().is_some(),
+            None => true,
+        };
+
+        if done {
+            pending.remove(&self.key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tokio::sync::oneshot;
+
+    #[tokio::test]
+    async fn test_concurrent_callers_share_work() {
+        let in_flight = InFlight::<&str, usize>::default();
+        let runs = AtomicUsize::new(0);
+        let (release_sender, release_receiver) = oneshot::channel::<()>();
+
+        // The futures are polled in order, so the second caller finds the work of the first one
+        let (first, second, _) = futures::join!(
+            in_flight.run("key", || {
+                let run = runs.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    release_receiver.await.unwrap();
+                    run
+                }
+            }),
+            in_flight.run("key", || {
+                let run = runs.fetch_add(1, Ordering::SeqCst);
+                async move { run }
+            }),
+            async { release_sender.send(()).unwrap() },
+        );
+
+        assert_eq!(first, (0, false));
+        assert_eq!(second, (0, true));
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+        assert!(in_flight.pending.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_finished_work_runs_again() {
+        let in_flight = InFlight::<&str, usize>::default();
+
+        assert_eq!(in_flight.run("key", || async { 1 }).await, (1, false));
+        assert_eq!(in_flight.run("key", || async { 2 }).await, (2, false));
+    }
+
+    #[tokio::test]
+    async fn test_work_is_dropped_without_callers() {
+        let in_flight = Arc::new(InFlight::<&str, ()>::default());
+        let (dropped_sender, dropped_receiver) = oneshot::channel::<()>();
+
+        let caller = {
+            let in_flight = in_flight.clone();
+            tokio::spawn(async move {
+                in_flight
+                    .run("key", || async move {
+                        // The sender is dropped along with the work
+                        let _dropped_sender = dropped_sender;
+                        futures::future::pending::<()>().await
+                    })
+                    .await
+            })
+        };
+
+        while in_flight.pending.lock().unwrap().is_empty() {
+            tokio::task::yield_now().await;
+        }
+
+        caller.abort();
+        assert!(dropped_receiver.await.is_err());
+        assert!(in_flight.pending.lock().unwrap().is_empty());
+    }
+}
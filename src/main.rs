@@ -1,52 +1,393 @@
+mod auth;
 mod available_packages_cache;
 mod cli;
+mod config;
+mod conflicts;
+mod credentials;
 mod dto;
 mod error;
 mod generic_cache;
+mod in_flight;
+mod rate_limit;
+mod shutdown;
+mod solve;
+mod solver_pool;
+mod tls;
 
-use crate::cli::Args;
-use crate::dto::{SolveEnvironment, SolveEnvironmentOk};
-use crate::error::{response_from_error, ApiError, ParseError, ParseErrors, ValidationError};
+use crate::auth::{Authenticator, Identity};
+use crate::config::{ChannelPolicy, ChannelRegistry, Config, ConfigSource, RateLimits};
+use crate::dto::{
+    ChannelPriority, ChannelRequest, ReloadConfigOk, RepoDataMetadata, SkippedChannel,
+    SolveEnvironment, SolveEnvironmentOk, SolveMetadata,
+};
+use crate::error::{
+    response_from_error, ApiError, ErrorCode, ParseError, ValidationError, ValidationErrors,
+};
 use anyhow::Context;
-use available_packages_cache::AvailablePackagesCache;
-use axum::extract::State;
+use arc_swap::ArcSwap;
+use available_packages_cache::{AvailablePackages, AvailablePackagesCache};
+use axum::body::{Body, Bytes};
+use axum::extract::{ConnectInfo, Request, State};
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
+use axum::middleware::{self, Next};
 use axum::response::{IntoResponse, Response};
-use axum::{routing::post, Json, Router};
-use clap::Parser;
+use axum::routing::{get, post};
+use axum::{Extension, Json, Router};
+use chrono::{DateTime, Utc};
 use cli::Solver;
-use futures::{StreamExt, TryStreamExt};
-use rattler_conda_types::{
-    Channel, ChannelConfig, GenericVirtualPackage, MatchSpec, PackageName, PackageRecord, Platform,
-    RepoDataRecord,
-};
-use rattler_solve::{libsolv_c, resolvo, SolverImpl, SolverTask};
+use futures::StreamExt;
+use generic_cache::{GenericCache, GetCachedResult};
+use in_flight::InFlight;
+use rate_limit::RateLimiter;
+use rattler_conda_types::{GenericVirtualPackage, MatchSpec, PackageName, PackageRecord, Platform};
+use rattler_solve::{libsolv_c, resolvo};
+use solve::{ExclusionSource, SolveOptions, SolveOutput};
+use solver_pool::SolverPool;
 
+use std::fmt::{Display, Formatter};
+use std::net::SocketAddr;
 use std::str::FromStr;
 use std::sync::Arc;
-use std::time::Duration;
-use tracing::{span, Instrument, Level};
+use std::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+use tracing::{event, span, Instrument, Level};
 use tracing_subscriber::fmt::format::{format, FmtSpan};
 
+/// The response header that tells clients how long their solve waited for a solver thread
+const QUEUE_WAIT_HEADER: &str = "x-solver-queue-wait-ms";
+
+/// The response header that tells clients whether their result came from the solve cache
+const SOLVE_CACHE_HEADER: &str = "x-solve-cache";
+
+/// The response header that tells clients their request shared the solve of an identical request
+const DEDUPLICATED_HEADER: &str = "x-solve-deduplicated";
+
 struct AppState {
     available_packages: AvailablePackagesCache,
+    rate_limiter: RateLimiter,
+    solver_pool: SolverPool,
+    /// The solves that are currently running, so identical requests arriving meanwhile can wait
+    /// for them instead of solving again
+    in_flight_solves: InFlight<SolveKey, BufferedResponse>,
+    /// The results of recent solves
+    solve_results: GenericCache<SolveResultKey, SolveOutput>,
+    /// The settings that can change when the configuration is reloaded
+    settings: ArcSwap<Settings>,
+    /// Where to load the configuration from when reloading it
+    config_source: Option<ConfigSource>,
+    /// The configuration the server was started with, whose restart-only settings stay in effect
+    /// until the next restart
+    startup_config: Config,
+    /// Cancelled once the server is shutting down and in-flight requests should stop waiting
+    shutdown: CancellationToken,
+}
+
+/// The part of the configuration that is swapped atomically on reload. Each request loads the
+/// settings once, so it sees a consistent snapshot even if a reload happens halfway.
+struct Settings {
     concurrent_repodata_downloads_per_request: usize,
-    channel_config: ChannelConfig,
+    channels: ChannelRegistry,
+    channel_policy: ChannelPolicy,
+    rate_limits: RateLimits,
+    authenticator: Authenticator,
+    solver: Solver,
+    /// How long solve results are cached, zero meaning not at all
+    solve_cache_expiration: Duration,
+    /// How long the solves of a request may take together
+    solve_timeout: Duration,
+    /// Whether to solve without the channels whose repodata cannot be fetched
+    skip_unavailable_channels: bool,
+    /// Whether to solve without the `noarch` platform of channels that do not have one
+    skip_missing_noarch: bool,
+    /// Packages younger than this are left out, if set
+    minimum_package_age: Option<chrono::Duration>,
+    /// Whether packages without a timestamp are left out, because of the minimum package age
+    exclude_undated_packages: bool,
+    /// Packages and builds that are never used
+    denied_packages: Vec<MatchSpec>,
+}
+
+impl Settings {
+    fn from_config(config: &Config) -> anyhow::Result<Settings> {
+        Ok(Settings {
+            concurrent_repodata_downloads_per_request: config
+                .concurrent_repodata_downloads_per_request,
+            channels: config.channel_registry()?,
+            channel_policy: config.channel_policy.clone(),
+            rate_limits: config.rate_limits.clone(),
+            authenticator: Authenticator::from_config(&config.auth)?,
+            solver: config.solver,
+            solve_cache_expiration: Duration::from_secs(config.solve_cache_expiration_seconds),
+            solve_timeout: Duration::from_secs(config.solve_timeout_seconds),
+            skip_unavailable_channels: config.skip_unavailable_channels,
+            skip_missing_noarch: config.skip_missing_noarch,
+            minimum_package_age: match config.minimum_package_age_days {
+                0 => None,
+                days => Some(chrono::Duration::days(days.into())),
+            },
+            exclude_undated_packages: config.minimum_package_age_days > 0
+                && !config.allow_undated_packages,
+            denied_packages: config.denied_packages()?,
+        })
+    }
+}
+
+/// Information collected while handling a solve request, which is reported to the client through
+/// response headers and the metadata of successful responses
+#[derive(Default)]
+struct SolveReport {
+    /// How long the request waited in the solver queue, if it got that far
+    queue_wait: Option<Duration>,
+    /// Whether the result came from the solve cache, if the cache was consulted
+    cache_hit: Option<bool>,
+    /// The channel platforms that were left out because their repodata could not be fetched
+    skipped_channels: Vec<SkippedChannel>,
+    /// The solver used, once the settings are loaded
     solver: Solver,
+    fetch_time: Duration,
+    solve_time: Duration,
+    /// The repodata used, as far as it was fetched
+    repodata: Vec<RepoDataMetadata>,
+    /// Packages published after this moment were left out
+    published_before: Option<DateTime<Utc>>,
+}
+
+impl SolveReport {
+    fn metadata(&self) -> SolveMetadata {
+        SolveMetadata {
+            server_version: env!("CARGO_PKG_VERSION").to_string(),
+            solver: self.solver,
+            fetch_ms: self.fetch_time.as_millis() as u64,
+            solve_ms: self.solve_time.as_millis() as u64,
+            solve_cache_hit: self.cache_hit.unwrap_or(false),
+            published_before: self.published_before,
+            repodata: self.repodata.clone(),
+        }
+    }
+}
+
+/// Identifies a solve result: the request, and the revisions of the repodata it was solved
+/// against. New repodata leads to a new key, so outdated results are never found.
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct SolveResultKey {
+    request: SolveKey,
+    repodata_revisions: Vec<String>,
 }
 
-/// Checks the `AvailablePackagesCache` every minute to remove outdated entries
+impl Display for SolveResultKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} [{}]",
+            self.request.platform,
+            self.request.specs.join(", ")
+        )
+    }
+}
+
+/// Identifies requests that are guaranteed to get the same answer. Specs and virtual packages are
+/// sorted, because their order does not matter, but the order of the channels does.
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct SolveKey {
+    platform: String,
+    specs: Vec<String>,
+    virtual_packages: Vec<String>,
+    channels: Vec<ChannelRequest>,
+    solver: Solver,
+    minimize_conflicts: bool,
+    as_of: Option<String>,
+    /// The server's minimum package age, which decides which packages are used along with `as_of`
+    minimum_package_age: Option<chrono::Duration>,
+    exclude_undated_packages: bool,
+    exclude: Vec<String>,
+    denied_packages: Vec<String>,
+    channel_priority: ChannelPriority,
+}
+
+impl SolveKey {
+    fn new(payload: &SolveEnvironment, settings: &Settings) -> SolveKey {
+        let canonical = |items: &[String]| {
+            let mut items: Vec<_> = items.iter().map(|item| item.trim().to_string()).collect();
+            items.sort();
+            items.dedup();
+            items
+        };
+
+        SolveKey {
+            platform: payload.platform.trim().to_string(),
+            specs: canonical(&payload.specs),
+            virtual_packages: canonical(&payload.virtual_packages),
+            channels: payload.channels.clone(),
+            solver: settings.solver,
+            minimize_conflicts: payload.minimize_conflicts,
+            as_of: payload.as_of.clone(),
+            minimum_package_age: settings.minimum_package_age,
+            exclude_undated_packages: settings.exclude_undated_packages,
+            exclude: canonical(&payload.exclude),
+            denied_packages: settings
+                .denied_packages
+                .iter()
+                .map(|spec| spec.to_string())
+                .collect(),
+            channel_priority: payload.channel_priority,
+        }
+    }
+}
+
+/// A response that has been read into memory, so it can be sent to several clients
+#[derive(Clone)]
+struct BufferedResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+impl BufferedResponse {
+    async fn from_response(response: Response) -> BufferedResponse {
+        let (parts, body) = response.into_parts();
+        match axum::body::to_bytes(body, usize::MAX).await {
+            Ok(body) => BufferedResponse {
+                status: parts.status,
+                headers: parts.headers,
+                body,
+            },
+            Err(e) => {
+                let e = anyhow::Error::from(e).context("unable to buffer response");
+                let (parts, _) = response_from_error(ApiError::Internal(e)).into_parts();
+                BufferedResponse {
+                    status: parts.status,
+                    headers: parts.headers,
+                    body: Bytes::new(),
+                }
+            }
+        }
+    }
+}
+
+impl IntoResponse for BufferedResponse {
+    fn into_response(self) -> Response {
+        let mut response = Response::new(Body::from(self.body));
+        *response.status_mut() = self.status;
+        *response.headers_mut() = self.headers;
+        response
+    }
+}
+
+impl AppState {
+    /// Loads the configuration again and applies it. The cached repodata is kept, but entries
+    /// cached from now on use the new expiration. Returns the changed settings that only take
+    /// effect after a restart.
+    fn reload_config(&self) -> anyhow::Result<Vec<&'static str>> {
+        let config = match &self.config_source {
+            Some(source) => source.load()?,
+            None => anyhow::bail!("the server was not started from a configuration source"),
+        };
+
+        let settings = Settings::from_config(&config)?;
+        self.available_packages.reconfigure(
+            Duration::from_secs(config.repodata_cache_expiration_seconds),
+            config.credentials.clone(),
+            config.channel_policy.clone(),
+        );
+        self.settings.store(Arc::new(settings));
+
+        let restart_required = self.startup_config.restart_only_changes(&config);
+        for setting in &restart_required {
+            event!(
+                Level::WARN,
+                "Setting `{setting}` changed, but only takes effect after a restart"
+            );
+        }
+
+        event!(Level::INFO, "Reloaded configuration");
+        Ok(restart_required)
+    }
+}
+
+/// Checks the `AvailablePackagesCache` and the solve cache every minute to remove outdated entries,
+/// and forgets about clients that are no longer being rate limited
 async fn cache_gc_task(state: Arc<AppState>) {
     let mut interval_timer = tokio::time::interval(Duration::from_secs(60));
     loop {
         interval_timer.tick().await;
         state.available_packages.gc();
+        state.solve_results.gc();
+        state.rate_limiter.gc(&state.settings.load().rate_limits);
+    }
+}
+
+/// Keeps the repodata of the channels configured with `prewarm` in the cache, so requests never
+/// have to wait for it to be downloaded
+async fn prewarm_task(state: Arc<AppState>) {
+    let mut interval_timer = tokio::time::interval(Duration::from_secs(60));
+    loop {
+        interval_timer.tick().await;
+        let settings = state.settings.load_full();
+        for configured in settings.channels.configured_channels() {
+            for &platform in &configured.settings.prewarm {
+                let result = state
+                    .available_packages
+                    .get(&configured.channel, platform, Some(&configured.settings))
+                    .await;
+
+                if let Err(e) = result {
+                    event!(
+                        Level::WARN,
+                        "Unable to prewarm {}: {e}",
+                        configured.channel.platform_url(platform)
+                    );
+                }
+            }
+        }
     }
 }
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    let args = Args::parse();
+/// Reloads the configuration every time the process receives SIGHUP
+#[cfg(unix)]
+async fn reload_config_on_sighup(state: Arc<AppState>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            event!(
+                Level::ERROR,
+                "Unable to listen for SIGHUP, config reload disabled: {e}"
+            );
+            return;
+        }
+    };
 
+    while hangup.recv().await.is_some() {
+        if let Err(e) = state.reload_config() {
+            event!(
+                Level::ERROR,
+                "Unable to reload configuration, keeping the previous one: {e:#}"
+            );
+        }
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    let source = ConfigSource::from_command_line()?;
+    let config = source.load()?;
+
+    if source.args().print_config {
+        print!("{}", toml::to_string_pretty(&config.redacted())?);
+        return Ok(());
+    }
+
+    let runtime = tokio::runtime::Runtime::new()?;
+    let result = runtime.block_on(serve(config, source));
+
+    // Solver threads that are still busy at this point belong to requests that were cancelled
+    // during shutdown, so nobody is waiting for their results anymore
+    runtime.shutdown_timeout(Duration::ZERO);
+
+    result
+}
+
+async fn serve(config: Config, source: ConfigSource) -> anyhow::Result<()> {
     // TODO: this is all right for prototyping, but we will want to use a different subscriber for
     // production
     let subscriber = tracing_subscriber::fmt()
@@ -56,123 +397,359 @@ async fn main() -> anyhow::Result<()> {
         .finish();
     tracing::subscriber::set_global_default(subscriber)?;
 
-    let state = Arc::new(state_from_args(&args));
+    let mut state = state_from_config(&config)?;
+    state.config_source = Some(source);
+    let state = Arc::new(state);
 
     tokio::spawn(cache_gc_task(state.clone()));
+    tokio::spawn(prewarm_task(state.clone()));
+    #[cfg(unix)]
+    tokio::spawn(reload_config_on_sighup(state.clone()));
 
-    let app = app(state);
+    let app = app(state.clone());
+    let addr = SocketAddr::new(config.host, config.port);
 
-    let listener = tokio::net::TcpListener::bind(format!("127.0.0.1:{}", args.port))
-        .await
-        .unwrap();
+    let handle = axum_server::Handle::new();
+    let grace_period = Duration::from_secs(config.shutdown_grace_period_seconds);
+    tokio::spawn(shutdown::graceful_on_signal(
+        handle.clone(),
+        grace_period,
+        state.shutdown.clone(),
+    ));
+
+    match config.tls {
+        Some(tls_paths) => {
+            let tls_config = tls::load(&tls_paths)
+                .await
+                .context("unable to load the TLS configuration")?;
 
-    axum::serve(listener, app.into_make_service()).await?;
+            #[cfg(unix)]
+            tokio::spawn(tls::reload_on_sighup(tls_config.clone(), tls_paths));
+
+            axum_server::bind_rustls(addr, tls_config)
+                .handle(handle)
+                .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+                .await?;
+        }
+        None => {
+            axum_server::bind(addr)
+                .handle(handle)
+                .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+                .await?;
+        }
+    }
+
+    // The downloads share the deadline of the requests: they get until the connections of the
+    // cancelled requests are closed
+    event!(Level::INFO, "Waiting for in-progress repodata downloads");
+    let deadline = async {
+        state.shutdown.cancelled().await;
+        tokio::time::sleep(shutdown::CANCELLATION_PERIOD).await;
+    };
+    tokio::select! {
+        _ = state.available_packages.flush() => {}
+        _ = deadline => {
+            event!(
+                Level::WARN,
+                "Repodata downloads did not finish in time, the on-disk cache may be incomplete"
+            );
+        }
+    }
+
+    event!(Level::INFO, "Shutdown complete");
 
     Ok(())
 }
 
-fn state_from_args(args: &Args) -> AppState {
-    let cache_expiration = Duration::from_secs(args.repodata_cache_expiration_seconds);
+fn state_from_config(config: &Config) -> anyhow::Result<AppState> {
+    let cache_expiration = Duration::from_secs(config.repodata_cache_expiration_seconds);
 
-    AppState {
-        available_packages: AvailablePackagesCache::new(cache_expiration, args.cache_dir.clone()),
-        concurrent_repodata_downloads_per_request: args.concurrent_repodata_downloads_per_request,
-        channel_config: ChannelConfig::default(),
-        solver: args.solver,
-    }
+    Ok(AppState {
+        rate_limiter: RateLimiter::default(),
+        solver_pool: SolverPool::new(config.solver_workers(), config.solver_queue_size),
+        in_flight_solves: InFlight::default(),
+        solve_results: GenericCache::with_expiration(Duration::from_secs(
+            config.solve_cache_expiration_seconds,
+        )),
+        available_packages: AvailablePackagesCache::new(
+            cache_expiration,
+            config.cache_dir.clone(),
+            config.credentials.clone(),
+            config.channel_policy.clone(),
+            config.repodata_parse_workers(),
+            config.repodata_download_timeout(),
+        ),
+        settings: ArcSwap::from_pointee(Settings::from_config(config)?),
+        config_source: None,
+        startup_config: config.clone(),
+        shutdown: CancellationToken::new(),
+    })
 }
 
 fn app(state: Arc<AppState>) -> Router {
-    Router::new()
-        .route("/solve", post(solve_environment))
+    let mut router = Router::new().route("/solve", post(solve_environment));
+    if state.startup_config.admin_api {
+        router = router.route("/admin/reload-config", post(reload_config));
+    }
+
+    router
+        .route_layer(middleware::from_fn_with_state(state.clone(), authenticate))
+        // Describes the API itself, so it is available without credentials
+        .route("/schema/errors", get(error_schema))
         .with_state(state)
 }
 
-#[tracing::instrument(level = "info", skip(state))]
+async fn error_schema() -> Json<serde_json::Value> {
+    Json(error::error_schema())
+}
+
+/// Rejects requests without valid credentials (if authentication is enabled) and makes the
+/// [`Identity`] of the client available to the handlers
+async fn authenticate(
+    State(state): State<Arc<AppState>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let identity = state
+        .settings
+        .load()
+        .authenticator
+        .authenticate(request.headers());
+
+    match identity {
+        Ok(identity) => {
+            request.extensions_mut().insert(identity);
+            next.run(request).await
+        }
+        Err(e) => {
+            event!(Level::INFO, "Rejected unauthenticated request: {e}");
+            response_from_error(ApiError::Unauthenticated(e))
+        }
+    }
+}
+
+#[tracing::instrument(level = "info", skip(state), fields(identity = %identity))]
+async fn reload_config(
+    State(state): State<Arc<AppState>>,
+    Extension(identity): Extension<Identity>,
+) -> Response {
+    if !state.settings.load().authenticator.is_admin(&identity) {
+        event!(Level::INFO, "Rejected admin request");
+        return response_from_error(ApiError::Forbidden);
+    }
+
+    match state.reload_config() {
+        Ok(restart_required) => Json(ReloadConfigOk {
+            restart_required: restart_required.into_iter().map(String::from).collect(),
+        })
+        .into_response(),
+        Err(e) => response_from_error(ApiError::InvalidConfig(e)),
+    }
+}
+
+#[tracing::instrument(level = "info", skip(state), fields(identity = %identity))]
 async fn solve_environment(
     State(state): State<Arc<AppState>>,
+    Extension(identity): Extension<Identity>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
     Json(payload): Json<SolveEnvironment>,
 ) -> Response {
-    let result = solve_environment_inner(state, payload).await;
-    match result {
-        Ok(packages) => Json(SolveEnvironmentOk { packages }).into_response(),
-        Err(e) => response_from_error(e),
+    let client = client_id(&identity, connect_info);
+    let _permit = match state
+        .rate_limiter
+        .acquire(&client, &state.settings.load().rate_limits)
+    {
+        Ok(permit) => permit,
+        Err(e) => {
+            event!(Level::INFO, "Rate limited request");
+            return response_from_error(ApiError::RateLimited(e.retry_after));
+        }
+    };
+
+    // Identical requests that arrive while this one is being solved wait for its response
+    let key = SolveKey::new(&payload, &state.settings.load());
+    let solve = || {
+        let state = state.clone();
+        async move {
+            // The solve is dropped once all clients waiting for it have disconnected, which
+            // cancels the token through the guard, so an abandoned solve does not keep a solver
+            // thread busy
+            let cancelled = state.shutdown.child_token();
+            let _cancel_on_drop = cancelled.clone().drop_guard();
+
+            let mut report = SolveReport::default();
+            let result = solve_environment_inner(state, payload, cancelled, &mut report).await;
+            let mut response = match result {
+                Ok(output) => Json(SolveEnvironmentOk {
+                    packages: output.packages,
+                    exclusions: output.exclusions,
+                    skipped_channels: report.skipped_channels.clone(),
+                    metadata: report.metadata(),
+                })
+                .into_response(),
+                Err(e) => response_from_error(e),
+            };
+
+            if let Some(queue_wait) = report.queue_wait {
+                response.headers_mut().insert(
+                    QUEUE_WAIT_HEADER,
+                    HeaderValue::from(queue_wait.as_millis() as u64),
+                );
+            }
+
+            if let Some(cache_hit) = report.cache_hit {
+                let value = if cache_hit { "hit" } else { "miss" };
+                response
+                    .headers_mut()
+                    .insert(SOLVE_CACHE_HEADER, HeaderValue::from_static(value));
+            }
+
+            BufferedResponse::from_response(response).await
+        }
+    };
+
+    let (response, deduplicated) = state.in_flight_solves.run(key, solve).await;
+    let mut response = response.into_response();
+    if deduplicated {
+        event!(Level::DEBUG, "Shared the solve of an identical request");
+        response
+            .headers_mut()
+            .insert(DEDUPLICATED_HEADER, HeaderValue::from_static("true"));
+    }
+
+    response
+}
+
+/// Identifies the client for rate limiting purposes: by identity if it authenticated, and by IP
+/// address otherwise. Unverified credentials are never used, because clients could pick a new one
+/// for every request to get a fresh bucket.
+fn client_id(identity: &Identity, connect_info: Option<ConnectInfo<SocketAddr>>) -> String {
+    match (identity, connect_info) {
+        (Identity::Anonymous, Some(ConnectInfo(addr))) => format!("ip:{}", addr.ip()),
+        (Identity::Anonymous, None) => "unknown".to_string(),
+        (identity, _) => format!("id:{identity}"),
     }
 }
 
 async fn solve_environment_inner(
     state: Arc<AppState>,
     payload: SolveEnvironment,
-) -> Result<Vec<RepoDataRecord>, ApiError> {
+    cancelled: CancellationToken,
+    report: &mut SolveReport,
+) -> Result<SolveOutput, ApiError> {
     let root_span = span!(Level::TRACE, "solve_environment");
     let _enter = root_span.enter();
 
+    let settings = state.settings.load_full();
+
+    // Validate all fields before giving up, so clients can fix all problems at once
+    let mut errors = Vec::new();
+    let parse_error = |field: String, input: &str, error: String| ParseError {
+        field,
+        input: input.to_string(),
+        error,
+    };
+
     // Get match specs
     let mut matchspecs = Vec::with_capacity(payload.specs.len());
-    let mut invalid_matchspecs = Vec::new();
-    for spec in &payload.specs {
+    for (i, spec) in payload.specs.iter().enumerate() {
         match MatchSpec::from_str(spec) {
             Ok(spec) => matchspecs.push(spec),
-            Err(e) => invalid_matchspecs.push(ParseError {
-                input: spec.to_string(),
-                error: e.to_string(),
-            }),
+            Err(e) => errors.push(ValidationError::MatchSpec(parse_error(
+                format!("specs[{i}]"),
+                spec,
+                e.to_string(),
+            ))),
         }
     }
 
-    // Forbid invalid matchspecs
-    if !invalid_matchspecs.is_empty() {
-        return Err(ApiError::Validation(ValidationError::MatchSpecs(
-            ParseErrors(invalid_matchspecs),
-        )));
+    // Get the specs of the packages to leave out, the server's first
+    let mut excluded_specs: Vec<_> = settings
+        .denied_packages
+        .iter()
+        .map(|spec| (spec.clone(), ExclusionSource::Policy))
+        .collect();
+    for (i, spec) in payload.exclude.iter().enumerate() {
+        match MatchSpec::from_str(spec) {
+            Ok(spec) => excluded_specs.push((spec, ExclusionSource::Request)),
+            Err(e) => errors.push(ValidationError::MatchSpec(parse_error(
+                format!("exclude[{i}]"),
+                spec,
+                e.to_string(),
+            ))),
+        }
     }
 
     // Get the virtual packages
     let mut virtual_packages = Vec::with_capacity(payload.virtual_packages.len());
-    for spec in &payload.virtual_packages {
-        virtual_packages
-            .push(parse_virtual_package(spec.as_str()).map_err(ValidationError::VirtualPackage)?);
+    for (i, spec) in payload.virtual_packages.iter().enumerate() {
+        match parse_virtual_package(spec) {
+            Ok(package) => virtual_packages.push(package),
+            Err(e) => errors.push(ValidationError::VirtualPackage(parse_error(
+                format!("virtual_packages[{i}]"),
+                spec,
+                e,
+            ))),
+        }
     }
 
-    // Parse channels
+    // Parse channels, and forbid the ones that the server policy does not allow
     let mut channels = Vec::new();
-    let mut invalid_channels = Vec::new();
-    for channel in &payload.channels {
-        match Channel::from_str(channel, &state.channel_config) {
-            Ok(c) => channels.push(c),
-            Err(e) => invalid_channels.push(ParseError {
-                input: channel.to_string(),
-                error: e.to_string(),
-            }),
+    for (i, request) in payload.channels.iter().enumerate() {
+        let field = format!("channels[{i}]");
+        let input = request.name();
+        match settings.channels.parse(input) {
+            Ok(channel) => match settings.channel_policy.check(&channel) {
+                Ok(()) => channels.push((channel, request.clone())),
+                Err(e) => errors.push(ValidationError::DisallowedChannel(parse_error(
+                    field, input, e,
+                ))),
+            },
+            Err(e) => errors.push(ValidationError::Channel(parse_error(
+                field,
+                input,
+                e.to_string(),
+            ))),
         }
     }
 
-    // Forbid invalid channels
-    if !invalid_channels.is_empty() {
-        return Err(ApiError::Validation(ValidationError::Channels(
-            ParseErrors(invalid_channels),
-        )));
-    }
-
     // Each channel contains multiple subdirectories. Users can specify the subdirectories they want
     // to use when specifying their channels. If the user didn't specify the default subdirectories
     // we use defaults based on the current platform.
     let target_platform = match Platform::from_str(&payload.platform) {
-        Ok(p) => p,
+        Ok(p) => Some(p),
         Err(e) => {
-            return Err(ApiError::Validation(ValidationError::Platform(
-                ParseError {
-                    input: payload.platform.to_string(),
-                    error: e.to_string(),
-                },
+            errors.push(ValidationError::Platform(parse_error(
+                "platform".to_string(),
+                &payload.platform,
+                e.to_string(),
+            )));
+            None
+        }
+    };
+
+    let as_of = match payload.as_of.as_deref().map(DateTime::parse_from_rfc3339) {
+        None => None,
+        Some(Ok(as_of)) => Some(as_of.with_timezone(&Utc)),
+        Some(Err(e)) => {
+            errors.push(ValidationError::Timestamp(parse_error(
+                "as_of".to_string(),
+                payload.as_of.as_deref().unwrap_or_default(),
+                e.to_string(),
             )));
+            None
         }
     };
 
+    let target_platform = match target_platform {
+        Some(platform) if errors.is_empty() => platform,
+        _ => return Err(ApiError::Validation(ValidationErrors(errors))),
+    };
+
     let default_platforms = &[target_platform, Platform::NoArch];
 
     // The (channel, platform) combinations that have their own repodata.json
-    let channels_and_platforms = channels.into_iter().flat_map(|channel| {
+    let channels_and_platforms = channels.into_iter().flat_map(|(channel, request)| {
         let platforms = channel
             .platforms
             .as_ref()
@@ -180,44 +757,178 @@ async fn solve_environment_inner(
             .unwrap_or(default_platforms)
             .to_vec();
 
-        platforms.into_iter().map(move |p| (channel.clone(), p))
+        platforms
+            .into_iter()
+            .map(move |p| (channel.clone(), request.clone(), p))
     });
 
     // Get the available packages for each (channel, platform) combination
-    let available_packages: Vec<_> = futures::stream::iter(channels_and_platforms)
-        .map(|(channel, platform)| {
-            let state = &state;
-            async move { state.available_packages.get(&channel, platform).await }
+    report.solver = settings.solver;
+    let fetch_start = Instant::now();
+    let mut downloads = futures::stream::iter(channels_and_platforms)
+        .map(|(channel, request, platform)| {
+            let state = state.clone();
+            let settings = settings.clone();
+            async move {
+                let channel_settings = settings.channels.settings(&channel);
+                let result = state
+                    .available_packages
+                    .get(&channel, platform, channel_settings)
+                    .await;
+                (request, platform, result)
+            }
         })
-        .buffer_unordered(state.concurrent_repodata_downloads_per_request)
-        .try_collect()
-        .await?;
-
-    // This call will block for hundreds of milliseconds, or longer
-    let result = tokio::task::spawn_blocking(move || {
-        let problem = SolverTask {
-            available_packages: &available_packages,
-            virtual_packages,
-            specs: matchspecs,
-            locked_packages: Vec::new(),
-            pinned_packages: Vec::new(),
-            timeout: Some(Duration::from_secs(20)),
+        // In order, because the order of the channels is their priority
+        .buffered(settings.concurrent_repodata_downloads_per_request);
+
+    let mut available_packages: Vec<Arc<AvailablePackages>> = Vec::new();
+    while let Some((request, platform, result)) = downloads.next().await {
+        let error = match result {
+            Ok((packages, cache_hit)) => {
+                report.repodata.push(RepoDataMetadata {
+                    channel: request.name().to_string(),
+                    platform: platform.to_string(),
+                    cache_hit,
+                    info: packages.info.clone(),
+                });
+                available_packages.push(packages);
+                continue;
+            }
+            Err(e) => e,
         };
 
-        match state.solver {
-            Solver::Resolvo => resolvo::Solver.solve(problem),
-            Solver::Libsolvc => libsolv_c::Solver.solve(problem),
+        // Optional channels, and any channel if the server says so, are left out when their
+        // repodata cannot be fetched. Other errors are not the channel's fault. With strict
+        // priority, leaving out a channel would let later channels provide the packages it
+        // shadows, except for a `noarch` platform that does not exist and has none.
+        let Some((code, reason)) = error.channel_failure() else {
+            return Err(error);
+        };
+        let missing_noarch = platform == Platform::NoArch && code == ErrorCode::ChannelNotFound;
+        let strict = payload.channel_priority == ChannelPriority::Strict;
+        let skip = (!strict && (request.optional() || settings.skip_unavailable_channels))
+            || (settings.skip_missing_noarch && missing_noarch);
+        if !skip {
+            return Err(error);
         }
-    })
-    .instrument(span!(Level::DEBUG, "solve"))
-    .await
-    .context("solver thread panicked")
-    .map_err(ApiError::Internal)?;
 
-    Ok(PackageRecord::sort_topologically(result?))
+        event!(
+            Level::WARN,
+            "Skipping {} ({platform}): {error}",
+            request.name()
+        );
+        report.skipped_channels.push(SkippedChannel {
+            channel: request.name().to_string(),
+            platform: platform.to_string(),
+            code,
+            reason,
+        });
+    }
+
+    report.fetch_time = fetch_start.elapsed();
+
+    // The policy and the request can both rule out recent packages, the earliest cutoff wins
+    let minimum_age_cutoff = settings
+        .minimum_package_age
+        .and_then(|age| Utc::now().checked_sub_signed(age));
+    let published_before = match (as_of, minimum_age_cutoff) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    };
+    report.published_before = published_before;
+
+    // Reuse the result of an earlier solve against the same repodata, if any
+    let mut repodata_revisions: Vec<_> = available_packages
+        .iter()
+        .map(|packages| packages.revision.clone())
+        .collect();
+    repodata_revisions.sort();
+    let result_key = SolveResultKey {
+        request: SolveKey::new(&payload, &settings),
+        repodata_revisions,
+    };
+
+    let write_token = if settings.solve_cache_expiration.is_zero() {
+        None
+    } else {
+        match state.solve_results.get_cached(&result_key).await {
+            GetCachedResult::Found(output) => {
+                report.cache_hit = Some(true);
+                return Ok(output.as_ref().clone());
+            }
+            GetCachedResult::NotFound(write_token) => {
+                report.cache_hit = Some(false);
+                Some(write_token)
+            }
+        }
+    };
+
+    let solver = settings.solver;
+    let timeout = settings.solve_timeout;
+    let exclude_undated = settings.exclude_undated_packages;
+    let minimize_conflicts = payload.minimize_conflicts;
+    let channel_priority = payload.channel_priority;
+
+    // This call will block for hundreds of milliseconds, or longer, so it runs on the solver pool
+    let solve_start = Instant::now();
+    let solve = state
+        .solver_pool
+        .submit(cancelled.clone(), move || {
+            // The records are borrowed from the cache, which is much cheaper than copying them
+            let options = SolveOptions {
+                timeout,
+                minimize_conflicts,
+                published_before,
+                exclude_undated,
+                excluded_specs,
+                channel_priority,
+                cancelled,
+            };
+
+            match solver {
+                Solver::Resolvo => solve::solve(
+                    resolvo::Solver,
+                    &available_packages,
+                    matchspecs,
+                    virtual_packages,
+                    &options,
+                ),
+                Solver::Libsolvc => solve::solve(
+                    libsolv_c::Solver,
+                    &available_packages,
+                    matchspecs,
+                    virtual_packages,
+                    &options,
+                ),
+            }
+        })
+        .map_err(|_| ApiError::Overloaded)?
+        .instrument(span!(Level::DEBUG, "solve"));
+
+    // Checked first, because the pool drops jobs that are cancelled by the shutdown
+    let (result, queue_wait) = tokio::select! {
+        biased;
+        _ = state.shutdown.cancelled() => return Err(ApiError::ShuttingDown),
+        result = solve => result.context("solver thread panicked").map_err(ApiError::Internal)?,
+    };
+    event!(Level::DEBUG, "Waited {queue_wait:?} for a solver thread");
+    report.queue_wait = Some(queue_wait);
+    report.solve_time = solve_start.elapsed().saturating_sub(queue_wait);
+
+    let mut output = result?;
+    output.packages = PackageRecord::sort_topologically(output.packages);
+    if let Some(write_token) = write_token {
+        state.solve_results.set_with_expiration(
+            write_token,
+            Arc::new(output.clone()),
+            settings.solve_cache_expiration,
+        );
+    }
+
+    Ok(output)
 }
 
-fn parse_virtual_package(virtual_package: &str) -> Result<GenericVirtualPackage, ParseError> {
+fn parse_virtual_package(virtual_package: &str) -> Result<GenericVirtualPackage, String> {
     let mut split = virtual_package.split('=');
 
     // Can unwrap first because split will always return at least one element
@@ -226,24 +937,15 @@ fn parse_virtual_package(virtual_package: &str) -> Result<GenericVirtualPackage,
         .next()
         .unwrap_or("0")
         .parse()
-        .map_err(|e| ParseError {
-            input: virtual_package.to_string(),
-            error: format!("invalid version - {e}"),
-        })?;
+        .map_err(|e| format!("invalid version - {e}"))?;
     let build_string = split.next().unwrap_or("0").to_string();
 
     if split.next().is_some() {
-        return Err(ParseError {
-            input: virtual_package.to_string(),
-            error: "too many equals signs".to_string(),
-        });
+        return Err("too many equals signs".to_string());
     }
 
     Ok(GenericVirtualPackage {
-        name: PackageName::try_from(name).map_err(|e| ParseError {
-            input: virtual_package.to_string(),
-            error: e.to_string(),
-        })?,
+        name: PackageName::try_from(name).map_err(|e| e.to_string())?,
         version,
         build_string,
     })
@@ -252,41 +954,59 @@ fn parse_virtual_package(virtual_package: &str) -> Result<GenericVirtualPackage,
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::AuthConfig;
     use axum::body::Body;
     use axum::http;
     use axum::http::{header, Request, StatusCode};
     use mktemp::Temp;
     use mockito::{Mock, ServerGuard};
     use reqwest::Url;
+    use rstest::rstest;
     use tower::util::ServiceExt;
 
     async fn dummy_app() -> (ServerGuard, Router) {
         let temp_dir = Temp::new_dir().unwrap();
         let cache_dir = temp_dir.to_path_buf();
-        let mut state = state_from_args(&Args {
+        let mock_channel_server = mockito::Server::new_async().await;
+        let state = state_from_config(&Config {
             concurrent_repodata_downloads_per_request: 1,
             repodata_cache_expiration_seconds: u64::MAX,
             // The port is ignored during testing
             port: 0,
             cache_dir,
             solver: Solver::Resolvo,
-        });
-
-        let mock_channel_server = mockito::Server::new_async().await;
-        state.channel_config = ChannelConfig {
             channel_alias: Url::parse(&mock_channel_server.url()).unwrap(),
-        };
+            ..Config::default()
+        })
+        .unwrap();
 
         (mock_channel_server, app(Arc::new(state)))
     }
 
+    /// Builds the state of an app that caches repodata in a temporary directory, which is removed
+    /// when the returned guard is dropped
+    fn test_state(config: Config) -> (Temp, Arc<AppState>) {
+        let temp_dir = Temp::new_dir().unwrap();
+        let state = state_from_config(&Config {
+            cache_dir: temp_dir.to_path_buf(),
+            ..config
+        })
+        .unwrap();
+
+        (temp_dir, Arc::new(state))
+    }
+
     fn default_solve_body() -> SolveEnvironment {
         SolveEnvironment {
             name: Some("dummy".to_string()),
             platform: "linux-64".to_string(),
             specs: Vec::new(),
-            channels: vec!["conda-forge".to_string()],
+            channels: vec!["conda-forge".into()],
             virtual_packages: Vec::new(),
+            minimize_conflicts: false,
+            as_of: None,
+            exclude: Vec::new(),
+            channel_priority: ChannelPriority::default(),
         }
     }
 
@@ -315,9 +1035,7 @@ mod tests {
             .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
             .body(json)
             .unwrap();
-        let response = app.oneshot(request).await.unwrap();
-
-        response
+        app.oneshot(request).await.unwrap()
     }
 
     async fn response_body(response: Response) -> String {
@@ -344,20 +1062,302 @@ mod tests {
         assert!(body.contains("asdfasdf"), "The response body did not mention the offending platform! See below for the full body:\n{body}");
     }
 
+    #[tokio::test]
+    async fn test_solve_reports_all_validation_errors() {
+        let body = SolveEnvironment {
+            platform: "asdfasdf".to_string(),
+            specs: vec!["foo".to_string(), "bar >=>=1".to_string()],
+            virtual_packages: vec!["__glibc=2.17=0=0".to_string()],
+            as_of: Some("yesterday".to_string()),
+            ..default_solve_body()
+        };
+        let (_mock_channel_server, app) = dummy_app().await;
+        let response = post_solve(app, body).await;
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = serde_json::from_str(&response_body(response).await).unwrap();
+        assert_eq!(body["code"], "invalid-spec");
+
+        let errors: Vec<_> = body["additional_info"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|e| (e["field"].as_str().unwrap(), e["code"].as_str().unwrap()))
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                ("specs[1]", "invalid-spec"),
+                ("virtual_packages[0]", "invalid-virtual-package"),
+                ("platform", "invalid-platform"),
+                ("as_of", "invalid-timestamp"),
+            ]
+        );
+    }
+
+    /// Makes the repodata of both platforms respond with the given status and body
+    async fn setup_failing_repodata_mocks(
+        mock_server: &mut ServerGuard,
+        status: usize,
+        body: &str,
+    ) -> Vec<Mock> {
+        let mut mocks = Vec::new();
+        for platform in ["linux-64", "noarch"] {
+            let mock = mock_server
+                .mock(
+                    "GET",
+                    format!("/conda-forge/{platform}/repodata.json").as_str(),
+                )
+                .with_status(status)
+                .with_body(body)
+                .create_async()
+                .await;
+            mocks.push(mock);
+        }
+        mocks
+    }
+
     #[tokio::test]
     async fn test_solve_channel_not_found() {
         let body = default_solve_body();
-        let (_mock_channel_server, app) = dummy_app().await;
+        let (mut mock_channel_server, app) = dummy_app().await;
+        let _mocks = setup_failing_repodata_mocks(&mut mock_channel_server, 404, "").await;
         let response = post_solve(app, body).await;
 
         assert_eq!(response.status(), StatusCode::BAD_REQUEST);
         let body = response_body(response).await;
         assert!(
-            body.contains("unable to retrieve repodata.json"),
+            body.contains(r#""code":"channel-not-found""#)
+                && body.contains(&mock_channel_server.url()),
             "Unexpected response! See below for the full body:\n{body}"
         );
     }
 
+    #[rstest]
+    #[case::unauthorized(403, "", "channel-unauthorized")]
+    #[case::server_error(500, "", "channel-unavailable")]
+    #[case::corrupt_json(200, "{\"packages\": [", "invalid-repodata")]
+    #[tokio::test]
+    async fn test_solve_channel_failure_is_upstream_error(
+        #[case] status: usize,
+        #[case] repodata: &str,
+        #[case] code: &str,
+    ) {
+        let (mut mock_channel_server, app) = dummy_app().await;
+        let _mocks = setup_failing_repodata_mocks(&mut mock_channel_server, status, repodata).await;
+        let response = post_solve(app, default_solve_body()).await;
+
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        let body = response_body(response).await;
+        assert!(
+            body.contains(&format!(r#""code":"{code}""#)),
+            "Unexpected response! See below for the full body:\n{body}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_solve_channel_denied_by_policy() {
+        let (_temp_dir, state) = test_state(Config {
+            channel_policy: ChannelPolicy {
+                allowed: Vec::new(),
+                denied_hosts: vec!["169.254.169.254".to_string()],
+            },
+            ..Config::default()
+        });
+
+        let body = SolveEnvironment {
+            channels: vec!["conda-forge".into(), "http://169.254.169.254/latest".into()],
+            ..default_solve_body()
+        };
+        let response = post_solve(app(state), body).await;
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = response_body(response).await;
+        assert!(
+            body.contains("http://169.254.169.254/latest") && !body.contains("conda-forge"),
+            "Unexpected response! See below for the full body:\n{body}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_solve_during_shutdown() {
+        let mut mock_channel_server = mockito::Server::new_async().await;
+        let _mocks = setup_repodata_mocks(&mut mock_channel_server).await;
+        let (_temp_dir, state) = test_state(Config {
+            channel_alias: Url::parse(&mock_channel_server.url()).unwrap(),
+            ..Config::default()
+        });
+
+        // The grace period is over
+        state.shutdown.cancel();
+        let response = post_solve(app(state), default_solve_body()).await;
+
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body = response_body(response).await;
+        assert!(
+            body.contains(r#""code":"shutting-down""#),
+            "Unexpected body!\n{body}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_solve_redirect_denied_by_policy() {
+        let mut allowed_server = mockito::Server::new_async().await;
+        let mut denied_server = mockito::Server::new_async().await;
+        let mut redirects = Vec::new();
+        let mut targets = Vec::new();
+        for platform in ["linux-64", "noarch"] {
+            let path = format!("/conda-forge/{platform}/repodata.json");
+            let redirect = allowed_server
+                .mock("GET", path.as_str())
+                .with_status(302)
+                .with_header("location", &format!("{}{path}", denied_server.url()))
+                .create_async()
+                .await;
+            let target = denied_server
+                .mock("GET", path.as_str())
+                .with_body(empty_repodata_json())
+                .expect(0)
+                .create_async()
+                .await;
+            redirects.push(redirect);
+            targets.push(target);
+        }
+
+        let (_temp_dir, state) = test_state(Config {
+            channel_alias: Url::parse(&allowed_server.url()).unwrap(),
+            channel_policy: ChannelPolicy {
+                allowed: vec![format!("{}/*", allowed_server.url())],
+                denied_hosts: Vec::new(),
+            },
+            ..Config::default()
+        });
+
+        let response = post_solve(app(state), default_solve_body()).await;
+
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        let body = response_body(response).await;
+        assert!(
+            body.contains(r#""code":"channel-unavailable""#),
+            "Unexpected body!\n{body}"
+        );
+        // The denied server is never contacted
+        for target in targets {
+            target.assert_async().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_solve_rate_limited() {
+        let (_temp_dir, state) = test_state(Config {
+            rate_limits: RateLimits {
+                requests_per_second: Some(0.1),
+                burst: 1,
+                max_concurrent_solves: None,
+            },
+            ..Config::default()
+        });
+        let app = app(state);
+
+        // The first request is let through (and fails, because its platform is invalid)
+        let body = SolveEnvironment {
+            platform: "asdfasdf".to_string(),
+            ..default_solve_body()
+        };
+        let response = post_solve(app.clone(), body.clone()).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        // The second one exceeds the rate limit
+        let response = post_solve(app, body).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "10");
+        let body = response_body(response).await;
+        assert!(
+            body.contains(r#""code":"rate-limited""#),
+            "Unexpected body!\n{body}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_solve_unauthenticated() {
+        let (_temp_dir, state) = test_state(Config {
+            auth: AuthConfig {
+                api_keys: [("team-a".to_string(), "secret".to_string())].into(),
+                ..AuthConfig::default()
+            },
+            ..Config::default()
+        });
+
+        let response = post_solve(app(state), default_solve_body()).await;
+
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(response.headers()[header::WWW_AUTHENTICATE], "Bearer");
+        let body = response_body(response).await;
+        assert!(
+            body.contains(r#""code":"unauthenticated""#),
+            "Unexpected body!\n{body}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reload_config_requires_admin() {
+        let (_temp_dir, state) = test_state(Config {
+            admin_api: true,
+            auth: AuthConfig {
+                api_keys: [
+                    ("team-a".to_string(), "secret".to_string()),
+                    ("ops".to_string(), "other-secret".to_string()),
+                ]
+                .into(),
+                admins: vec!["key:ops".to_string()],
+                ..AuthConfig::default()
+            },
+            ..Config::default()
+        });
+        let app = app(state);
+        let reload = |api_key: &str| {
+            Request::builder()
+                .uri("/admin/reload-config")
+                .method(http::Method::POST)
+                .header("x-api-key", api_key)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = app.clone().oneshot(reload("secret")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let body = response_body(response).await;
+        assert!(
+            body.contains(r#""code":"forbidden""#),
+            "Unexpected body!\n{body}"
+        );
+
+        // Admins get through, and fail because the server has no config file to reload
+        let response = app.oneshot(reload("other-secret")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[tokio::test]
+    async fn test_error_schema_is_public() {
+        let (_temp_dir, state) = test_state(Config {
+            auth: AuthConfig {
+                api_keys: [("team-a".to_string(), "secret".to_string())].into(),
+                ..AuthConfig::default()
+            },
+            ..Config::default()
+        });
+
+        let request = Request::get("/schema/errors").body(Body::empty()).unwrap();
+        let response = app(state).oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response_body(response).await;
+        assert!(
+            body.contains(r#""const":"unsolvable""#),
+            "Unexpected body!\n{body}"
+        );
+    }
+
     #[tokio::test]
     async fn test_solve_happy_path() {
         let (mut mock_channel_server, app) = dummy_app().await;
@@ -375,6 +1375,7 @@ mod tests {
         }
 
         assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().contains_key(QUEUE_WAIT_HEADER));
         let body = response_body(response).await;
         let body: SolveEnvironmentOk = serde_json::from_str(&body).unwrap();
 
@@ -384,6 +1385,194 @@ mod tests {
             .map(|p| p.package_record.name.as_normalized())
             .collect();
         assert_eq!(resolved_package_names, vec!["foo", "bar"]);
+
+        let metadata = body.metadata;
+        assert_eq!(metadata.server_version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(metadata.solver, Solver::Resolvo);
+        assert!(!metadata.solve_cache_hit);
+        let platforms: Vec<_> = metadata
+            .repodata
+            .iter()
+            .map(|r| (r.platform.as_str(), r.cache_hit))
+            .collect();
+        assert_eq!(platforms, vec![("linux-64", false), ("noarch", false)]);
+    }
+
+    #[tokio::test]
+    async fn test_solve_skips_unavailable_optional_channel() {
+        let (mut mock_channel_server, app) = dummy_app().await;
+        let _mock_endpoints = setup_repodata_mocks(&mut mock_channel_server).await;
+        let _missing_endpoint = mock_channel_server
+            .mock("GET", mockito::Matcher::Regex("^/internal/".to_string()))
+            .with_status(404)
+            .create_async()
+            .await;
+
+        let body = SolveEnvironment {
+            virtual_packages: vec!["__unix".to_string()],
+            specs: vec!["foo".to_string()],
+            channels: vec![
+                "conda-forge".into(),
+                ChannelRequest::WithOptions {
+                    name: "internal".to_string(),
+                    optional: true,
+                },
+            ],
+            ..default_solve_body()
+        };
+        let response = post_solve(app, body).await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response_body(response).await;
+        let body: SolveEnvironmentOk = serde_json::from_str(&body).unwrap();
+
+        assert_eq!(body.packages.len(), 1);
+        let mut skipped: Vec<_> = body
+            .skipped_channels
+            .iter()
+            .map(|s| (s.channel.as_str(), s.platform.as_str(), s.code))
+            .collect();
+        skipped.sort_by_key(|&(_, platform, _)| platform);
+        assert_eq!(
+            skipped,
+            vec![
+                ("internal", "linux-64", ErrorCode::ChannelNotFound),
+                ("internal", "noarch", ErrorCode::ChannelNotFound),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_strict_priority_does_not_skip_channels() {
+        let (mut mock_channel_server, app) = dummy_app().await;
+        let _mock_endpoints = setup_repodata_mocks(&mut mock_channel_server).await;
+        let _missing_endpoint = mock_channel_server
+            .mock("GET", mockito::Matcher::Regex("^/internal/".to_string()))
+            .with_status(404)
+            .create_async()
+            .await;
+
+        // Skipping `internal` would let its package names be taken from `conda-forge`
+        let body = SolveEnvironment {
+            virtual_packages: vec!["__unix".to_string()],
+            specs: vec!["foo".to_string()],
+            channels: vec![
+                ChannelRequest::WithOptions {
+                    name: "internal".to_string(),
+                    optional: true,
+                },
+                "conda-forge".into(),
+            ],
+            channel_priority: ChannelPriority::Strict,
+            ..default_solve_body()
+        };
+        let response = post_solve(app, body).await;
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = response_body(response).await;
+        assert!(
+            body.contains(r#""code":"channel-not-found""#),
+            "Unexpected response! See below for the full body:\n{body}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_solve_reports_excluded_candidates() {
+        let (mut mock_channel_server, app) = dummy_app().await;
+        let _mock_endpoints = setup_repodata_mocks(&mut mock_channel_server).await;
+
+        let body = SolveEnvironment {
+            specs: vec!["foo".to_string()],
+            exclude: vec!["foo >=3".to_string()],
+            ..default_solve_body()
+        };
+        let response = post_solve(app, body).await;
+
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let body: serde_json::Value = serde_json::from_str(&response_body(response).await).unwrap();
+        let exclusions = &body["additional_info"]["exclusions"];
+        assert_eq!(exclusions[0]["spec"], "foo >=3");
+        assert_eq!(exclusions[0]["source"], "request");
+        assert_eq!(exclusions[0]["count"], 1);
+    }
+
+    #[tokio::test]
+    async fn test_identical_concurrent_solves_are_deduplicated() {
+        let (mut mock_channel_server, app) = dummy_app().await;
+        let _mock_endpoints = setup_repodata_mocks(&mut mock_channel_server).await;
+
+        let body = SolveEnvironment {
+            specs: vec!["foo".to_string(), "bar".to_string()],
+            virtual_packages: vec!["__unix".to_string()],
+            ..default_solve_body()
+        };
+        // Same request, with the specs in a different order
+        let reordered_body = SolveEnvironment {
+            specs: vec!["bar".to_string(), "foo".to_string()],
+            ..body.clone()
+        };
+
+        let (first, second) = futures::join!(
+            post_solve(app.clone(), body),
+            post_solve(app, reordered_body)
+        );
+
+        assert_eq!(first.status(), StatusCode::OK);
+        assert_eq!(second.status(), StatusCode::OK);
+        assert!(!first.headers().contains_key(DEDUPLICATED_HEADER));
+        assert_eq!(second.headers()[DEDUPLICATED_HEADER], "true");
+        assert_eq!(response_body(first).await, response_body(second).await);
+    }
+
+    #[tokio::test]
+    async fn test_failed_solve_is_not_left_as_active_write() {
+        let mut mock_channel_server = mockito::Server::new_async().await;
+        let _mocks = setup_repodata_mocks(&mut mock_channel_server).await;
+        let (_temp_dir, state) = test_state(Config {
+            channel_alias: Url::parse(&mock_channel_server.url()).unwrap(),
+            ..Config::default()
+        });
+
+        // `bar` depends on `__unix`, which the request does not provide
+        let body = SolveEnvironment {
+            specs: vec!["bar".to_string()],
+            ..default_solve_body()
+        };
+        let response = post_solve(app(state.clone()), body).await;
+
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        assert_eq!(state.solve_results.active_write_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_repeated_solve_is_served_from_cache() {
+        let (mut mock_channel_server, app) = dummy_app().await;
+        let _mock_endpoints = setup_repodata_mocks(&mut mock_channel_server).await;
+
+        let body = SolveEnvironment {
+            specs: vec!["foo".to_string()],
+            ..default_solve_body()
+        };
+
+        let first = post_solve(app.clone(), body.clone()).await;
+        assert_eq!(first.status(), StatusCode::OK);
+        assert_eq!(first.headers()[SOLVE_CACHE_HEADER], "miss");
+
+        let second = post_solve(app, body).await;
+        assert_eq!(second.status(), StatusCode::OK);
+        assert_eq!(second.headers()[SOLVE_CACHE_HEADER], "hit");
+        assert!(!second.headers().contains_key(QUEUE_WAIT_HEADER));
+
+        // Only the metadata tells the responses apart
+        let first: SolveEnvironmentOk = serde_json::from_str(&response_body(first).await).unwrap();
+        let second: SolveEnvironmentOk =
+            serde_json::from_str(&response_body(second).await).unwrap();
+        assert_eq!(
+            serde_json::to_value(&first.packages).unwrap(),
+            serde_json::to_value(&second.packages).unwrap()
+        );
+        assert!(second.metadata.solve_cache_hit);
+        assert!(second.metadata.repodata.iter().all(|r| r.cache_hit));
     }
 
     #[tokio::test]
@@ -407,7 +1596,69 @@ mod tests {
         assert!(
             body.contains("bar * cannot be installed because there are no viable options"),
             "Unexpected body!\n{body}"
-        )
+        );
+
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let conflicts = &body["additional_info"]["conflicts"];
+        assert_eq!(conflicts[0]["kind"], "missing");
+        assert_eq!(conflicts[0]["requirement"]["spec"], "__unix");
+        assert!(body["additional_info"]["explanation"]
+            .as_str()
+            .unwrap()
+            .contains("nothing provides `__unix`"));
+    }
+
+    #[tokio::test]
+    async fn test_reload_config_keeps_cache() {
+        let mut mock_channel_server = mockito::Server::new_async().await;
+        let mock_endpoints = setup_repodata_mocks(&mut mock_channel_server).await;
+
+        let temp_dir = Temp::new_dir().unwrap();
+        let config_path = temp_dir.to_path_buf().join("config.toml");
+        let write_config = |solver: &str, port: u16| {
+            let config = format!(
+                "admin_api = true\nsolver = \"{solver}\"\nport = {port}\ncache_dir = {:?}\nchannel_alias = \"{}\"\n",
+                temp_dir.to_path_buf(),
+                mock_channel_server.url()
+            );
+            std::fs::write(&config_path, config).unwrap();
+        };
+        write_config("resolvo", 3000);
+
+        let source =
+            ConfigSource::from_argv(["rattler-server", "--config", config_path.to_str().unwrap()]);
+        let mut state = state_from_config(&source.load().unwrap()).unwrap();
+        state.config_source = Some(source);
+        let state = Arc::new(state);
+
+        let body = SolveEnvironment {
+            virtual_packages: vec!["__unix".to_string()],
+            specs: vec!["foo".to_string()],
+            ..default_solve_body()
+        };
+        let response = post_solve(app(state.clone()), body.clone()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Switch solvers through the admin endpoint. The port cannot change without a restart.
+        write_config("libsolvc", 3001);
+        let request = Request::builder()
+            .uri("/admin/reload-config")
+            .method(http::Method::POST)
+            .body(Body::empty())
+            .unwrap();
+        let response = app(state.clone()).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let reloaded: ReloadConfigOk =
+            serde_json::from_str(&response_body(response).await).unwrap();
+        assert_eq!(reloaded.restart_required, vec!["port"]);
+        assert!(matches!(state.settings.load().solver, Solver::Libsolvc));
+
+        // The repodata is still cached, so the channel is not hit a second time
+        let response = post_solve(app(state), body).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        for endpoint in mock_endpoints {
+            endpoint.assert_async().await;
+        }
     }
 
     fn empty_repodata_json() -> String {
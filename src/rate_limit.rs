@@ -0,0 +1,170 @@
+//! Contains the per-client rate limiting applied to `/solve`
+
+use crate::config::RateLimits;
+use dashmap::DashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// How long to tell clients to wait when they exceed their concurrent solve quota. There is no way
+/// to know when one of their solves finishes, so this is a guess.
+const CONCURRENCY_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+/// Keeps track of the requests made by each client
+#[derive(Default)]
+pub struct RateLimiter {
+    buckets: DashMap<String, TokenBucket>,
+    active_solves: DashMap<String, Arc<AtomicUsize>>,
+}
+
+/// Returned when a client exceeds its limits
+#[derive(Debug)]
+pub struct RateLimited {
+    pub retry_after: Duration,
+}
+
+/// Counts as one of the client's concurrent solves until dropped
+pub struct SolvePermit {
+    active_solves: Option<Arc<AtomicUsize>>,
+}
+
+impl Drop for SolvePermit {
+    fn drop(&mut self) {
+        if let Some(active_solves) = &self.active_solves {
+            active_solves.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    /// Registers a request from the client, failing if the client exceeds the limits. The request
+    /// counts towards the client's concurrent solves until the returned permit is dropped.
+    pub fn acquire(&self, client: &str, limits: &RateLimits) -> Result<SolvePermit, RateLimited> {
+        self.acquire_at(client, limits, Instant::now())
+    }
+
+    fn acquire_at(
+        &self,
+        client: &str,
+        limits: &RateLimits,
+        now: Instant,
+    ) -> Result<SolvePermit, RateLimited> {
+        // Check the concurrency quota first, so rejected requests don't consume tokens
+        let active_solves = match limits.max_concurrent_solves {
+            Some(max) => {
+                let active_solves = self
+                    .active_solves
+                    .entry(client.to_string())
+                    .or_default()
+                    .clone();
+
+                if active_solves.fetch_add(1, Ordering::SeqCst) >= max {
+                    active_solves.fetch_sub(1, Ordering::SeqCst);
+                    return Err(RateLimited {
+                        retry_after: CONCURRENCY_RETRY_AFTER,
+                    });
+                }
+
+                Some(active_solves)
+            }
+            None => None,
+        };
+
+        // From here on, dropping the permit releases the concurrency slot
+        let permit = SolvePermit { active_solves };
+
+        if let Some(rate) = limits.requests_per_second {
+            let capacity = limits.burst.max(1) as f64;
+            let mut bucket =
+                self.buckets
+                    .entry(client.to_string())
+                    .or_insert_with(|| TokenBucket {
+                        tokens: capacity,
+                        last_refill: now,
+                    });
+
+            let elapsed = now.saturating_duration_since(bucket.last_refill);
+            bucket.tokens = (bucket.tokens + elapsed.as_secs_f64() * rate).min(capacity);
+            bucket.last_refill = now;
+
+            if bucket.tokens < 1.0 {
+                return Err(RateLimited {
+                    retry_after: Duration::from_secs_f64((1.0 - bucket.tokens) / rate),
+                });
+            }
+
+            bucket.tokens -= 1.0;
+        }
+
+        Ok(permit)
+    }
+
+    /// Forgets about clients that are idle, i.e. have a full bucket and no active solves
+    pub fn gc(&self, limits: &RateLimits) {
+        let now = Instant::now();
+        self.active_solves
+            .retain(|_, active_solves| active_solves.load(Ordering::SeqCst) > 0);
+
+        match limits.requests_per_second {
+            Some(rate) => {
+                let capacity = limits.burst.max(1) as f64;
+                self.buckets.retain(|_, bucket| {
+                    let elapsed = now.saturating_duration_since(bucket.last_refill);
+                    bucket.tokens + elapsed.as_secs_f64() * rate < capacity
+                });
+            }
+            None => self.buckets.clear(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let limiter = RateLimiter::default();
+        let limits = RateLimits {
+            requests_per_second: Some(2.0),
+            burst: 2,
+            max_concurrent_solves: None,
+        };
+        let start = Instant::now();
+
+        // The burst is available right away
+        assert!(limiter.acquire_at("a", &limits, start).is_ok());
+        assert!(limiter.acquire_at("a", &limits, start).is_ok());
+        let rejected = limiter.acquire_at("a", &limits, start).err().unwrap();
+        assert_eq!(rejected.retry_after, Duration::from_millis(500));
+
+        // Other clients have their own bucket
+        assert!(limiter.acquire_at("b", &limits, start).is_ok());
+
+        // Tokens are refilled over time
+        let later = start + Duration::from_millis(500);
+        assert!(limiter.acquire_at("a", &limits, later).is_ok());
+        assert!(limiter.acquire_at("a", &limits, later).is_err());
+    }
+
+    #[test]
+    fn test_concurrent_solves() {
+        let limiter = RateLimiter::default();
+        let limits = RateLimits {
+            requests_per_second: None,
+            burst: 0,
+            max_concurrent_solves: Some(1),
+        };
+
+        let permit = limiter.acquire("a", &limits).unwrap();
+        assert!(limiter.acquire("a", &limits).is_err());
+
+        drop(permit);
+        assert!(limiter.acquire("a", &limits).is_ok());
+    }
+}
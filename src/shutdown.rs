@@ -0,0 +1,70 @@
+//! Contains the logic to shut the server down without killing the solves that are in progress
+
+use axum_server::Handle;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use tracing::{event, Level};
+
+/// Completes once the process receives SIGTERM or SIGINT
+pub async fn signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            event!(Level::ERROR, "Unable to listen for SIGINT: {e}");
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                event!(Level::ERROR, "Unable to listen for SIGTERM: {e}");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+/// How long requests that were cancelled at the end of the grace period get to send their error
+/// response, before their connections are closed
+pub const CANCELLATION_PERIOD: Duration = Duration::from_secs(5);
+
+/// Waits for a shutdown signal and then tells the server to stop accepting connections. Requests
+/// that are in flight get `grace_period` to finish. After that, `cancel` is cancelled so the
+/// remaining requests answer with an error, and their connections are closed
+/// [`CANCELLATION_PERIOD`] later.
+pub async fn graceful_on_signal(handle: Handle, grace_period: Duration, cancel: CancellationToken) {
+    signal().await;
+
+    event!(
+        Level::INFO,
+        "Shutdown requested, waiting up to {}s for {} open connection(s)",
+        grace_period.as_secs(),
+        handle.connection_count()
+    );
+    handle.graceful_shutdown(None);
+
+    tokio::time::sleep(grace_period).await;
+    event!(
+        Level::INFO,
+        "Grace period expired, cancelling {} remaining connection(s)",
+        handle.connection_count()
+    );
+    cancel.cancel();
+
+    tokio::time::sleep(CANCELLATION_PERIOD).await;
+    handle.shutdown();
+}
@@ -0,0 +1,815 @@
+//! Contains the code that hands the cached repodata to the solver
+
+use crate::available_packages_cache::AvailablePackages;
+use crate::conflicts;
+use crate::dto::ChannelPriority;
+use crate::error::ApiError;
+use chrono::{DateTime, Utc};
+use rattler_conda_types::{GenericVirtualPackage, MatchSpec, RepoDataRecord};
+use rattler_solve::{libsolv_c, resolvo, SolveError, SolverImpl, SolverTask};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+use url::Url;
+
+/// How to run the solver
+pub struct SolveOptions {
+    /// How long the solves may take together. Only some solvers can be stopped halfway (see
+    /// [`SolverFeatures`]), for the others it only limits the number of solves.
+    pub timeout: Duration,
+    /// Whether to look for a minimal set of conflicting specs when the environment is unsolvable,
+    /// which takes an extra solve per spec
+    pub minimize_conflicts: bool,
+    /// Leave out records published after this moment
+    pub published_before: Option<DateTime<Utc>>,
+    /// Also leave out records without a timestamp, whose age is unknown. They are kept otherwise,
+    /// which is right for `as_of` but not for the minimum package age.
+    pub exclude_undated: bool,
+    /// Leave out records matching any of these specs
+    pub excluded_specs: Vec<(MatchSpec, ExclusionSource)>,
+    /// With strict priority, records of lower-priority channels are left out (see
+    /// [`apply_strict_priority`])
+    pub channel_priority: ChannelPriority,
+    /// Cancelled once nobody is waiting for the result anymore. The solver cannot be interrupted,
+    /// but no further solves are started, e.g. to minimize the conflicts.
+    pub cancelled: CancellationToken,
+}
+
+/// What the solvers of `rattler_solve` support beyond [`SolverImpl`]
+pub trait SolverFeatures: SolverImpl {
+    /// Whether the solver gives up once `SolverTask::timeout` has passed. The others refuse to
+    /// solve if a timeout is given.
+    const SUPPORTS_TIMEOUT: bool;
+    /// Whether the solver always takes each package from the first channel that has it
+    const ALWAYS_STRICT: bool;
+}
+
+impl SolverFeatures for resolvo::Solver {
+    const SUPPORTS_TIMEOUT: bool = true;
+    const ALWAYS_STRICT: bool = true;
+}
+
+impl SolverFeatures for libsolv_c::Solver {
+    const SUPPORTS_TIMEOUT: bool = false;
+    const ALWAYS_STRICT: bool = false;
+}
+
+/// The packages of a solved environment
+#[derive(Clone)]
+pub struct SolveOutput {
+    pub packages: Vec<RepoDataRecord>,
+    /// The candidates that were left out because of `excluded_specs` or `exclude_undated`
+    pub exclusions: Vec<Exclusion>,
+}
+
+/// How many of the records left out by an excluded spec are named in the response
+const MAX_EXCLUDED_RECORDS: usize = 20;
+
+/// The records that were left out because they match an excluded spec. Only the records that the
+/// solver could have used are counted.
+#[cfg_attr(test, derive(serde::Deserialize))]
+#[derive(Clone, Debug, Serialize)]
+pub struct Exclusion {
+    pub spec: String,
+    pub source: ExclusionSource,
+    /// How many records were left out
+    pub count: usize,
+    /// The first few records that were left out
+    pub records: Vec<String>,
+}
+
+/// Who asked to leave out records
+#[cfg_attr(test, derive(serde::Deserialize))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExclusionSource {
+    /// The server's deny-list
+    Policy,
+    /// The `exclude` list of the request
+    Request,
+    /// The server's minimum package age, which leaves out records without a timestamp. The spec is
+    /// always `*`.
+    MissingTimestamp,
+}
+
+/// Solves the environment, giving the solver only the records it could possibly need (see
+/// [`reachable_records`]). Building the solver's pool is often more expensive than the solve
+/// itself, so this saves a lot of time for requests with a handful of specs.
+///
+/// With flexible channel priority, the environment is solved with strict priority first, and only
+/// if that is unsolvable with all channels. Both solves share the timeout.
+pub fn solve<S: SolverFeatures>(
+    mut solver: S,
+    available_packages: &[Arc<AvailablePackages>],
+    specs: Vec<MatchSpec>,
+    virtual_packages: Vec<GenericVirtualPackage>,
+    options: &SolveOptions,
+) -> Result<SolveOutput, ApiError> {
+    let deadline = Instant::now() + options.timeout;
+    let remaining = || deadline.saturating_duration_since(Instant::now());
+
+    let all_reachable = reachable_records(available_packages, &specs, &virtual_packages);
+    let mut strict = options.channel_priority != ChannelPriority::Disabled;
+    let (mut reachable, mut exclusions) = candidates(all_reachable.clone(), options, strict);
+    if options.cancelled.is_cancelled() {
+        return Err(ApiError::Solver(SolveError::Cancelled));
+    }
+    let mut result = run(
+        &mut solver,
+        &reachable,
+        specs.clone(),
+        virtual_packages.clone(),
+        strict,
+        remaining(),
+    );
+
+    if options.channel_priority == ChannelPriority::Flexible
+        && matches!(result, Err(SolveError::Unsolvable(_)))
+    {
+        // Packages that the first channel has may still have to come from a later channel
+        if options.cancelled.is_cancelled() {
+            return Err(ApiError::Solver(SolveError::Cancelled));
+        }
+        strict = false;
+        (reachable, exclusions) = candidates(all_reachable, options, strict);
+        result = run(
+            &mut solver,
+            &reachable,
+            specs.clone(),
+            virtual_packages.clone(),
+            strict,
+            remaining(),
+        );
+    }
+
+    let packages = result.map_err(|e| match e {
+        SolveError::Unsolvable(messages) => {
+            let mut unsolvable =
+                conflicts::explain(&reachable, &specs, &virtual_packages, messages);
+
+            if options.minimize_conflicts {
+                // The records reachable from all specs are also enough for any subset of them
+                let mut is_solvable =
+                    |specs: &[MatchSpec], virtual_packages: &[_], timeout: Duration| {
+                        let result = run(
+                            &mut solver,
+                            &reachable,
+                            specs.to_vec(),
+                            virtual_packages.to_vec(),
+                            strict,
+                            timeout,
+                        );
+                        match result {
+                            Ok(_) => Some(true),
+                            Err(SolveError::Unsolvable(_)) => Some(false),
+                            Err(_) => None,
+                        }
+                    };
+                // Minimizing gets whatever is left of the time a single solve may take
+                unsolvable.minimal_conflict = conflicts::minimize(
+                    &specs,
+                    &virtual_packages,
+                    deadline,
+                    &options.cancelled,
+                    &mut is_solvable,
+                );
+            }
+
+            for exclusion in &exclusions {
+                let source = match exclusion.source {
+                    ExclusionSource::Policy => "the server's deny-list",
+                    ExclusionSource::Request => "the request",
+                    ExclusionSource::MissingTimestamp => {
+                        "the minimum package age, because they have no timestamp"
+                    }
+                };
+                unsolvable.explanation.push_str(&format!(
+                    "\n{} candidates matching `{}` were excluded by {source}",
+                    exclusion.count, exclusion.spec
+                ));
+            }
+            unsolvable.exclusions = exclusions.clone();
+            ApiError::Unsolvable(Box::new(unsolvable))
+        }
+        e => ApiError::Solver(e),
+    })?;
+
+    Ok(SolveOutput {
+        packages,
+        exclusions,
+    })
+}
+
+/// Leaves out the reachable records that the solver may not use: those of lower-priority channels
+/// if `strict`, those published too late, and the excluded ones
+fn candidates<'a>(
+    mut reachable: Vec<Vec<&'a RepoDataRecord>>,
+    options: &SolveOptions,
+    strict: bool,
+) -> (Vec<Vec<&'a RepoDataRecord>>, Vec<Exclusion>) {
+    if strict {
+        // Before anything else is left out, so a package that is excluded from a channel is not
+        // taken from another one instead
+        apply_strict_priority(&mut reachable);
+    }
+    let mut undated = Exclusion {
+        spec: "*".to_string(),
+        source: ExclusionSource::MissingTimestamp,
+        count: 0,
+        records: Vec::new(),
+    };
+    if let Some(published_before) = options.published_before {
+        for records in &mut reachable {
+            records.retain(|record| match record.package_record.timestamp {
+                Some(timestamp) => timestamp <= published_before,
+                None if options.exclude_undated => {
+                    undated.count += 1;
+                    if undated.records.len() < MAX_EXCLUDED_RECORDS {
+                        undated.records.push(record.package_record.to_string());
+                    }
+                    false
+                }
+                None => true,
+            });
+        }
+    }
+    let mut exclusions = exclude_records(&mut reachable, &options.excluded_specs);
+    if undated.count > 0 {
+        exclusions.push(undated);
+    }
+
+    (reachable, exclusions)
+}
+
+/// Leaves out the records of each package that do not come from the first channel that has the
+/// package. The records are grouped in channel order, with one group per channel and platform.
+fn apply_strict_priority(reachable: &mut [Vec<&RepoDataRecord>]) {
+    let mut channels: HashMap<&str, &str> = HashMap::new();
+    for record in reachable.iter().flatten() {
+        channels
+            .entry(record.package_record.name.as_normalized())
+            .or_insert(record.channel.as_str());
+    }
+
+    for records in reachable.iter_mut() {
+        records.retain(|record| {
+            channels[record.package_record.name.as_normalized()] == record.channel.as_str()
+        });
+    }
+}
+
+/// Removes the records matching any of the specs, and tells which records each spec removed
+fn exclude_records(
+    reachable: &mut [Vec<&RepoDataRecord>],
+    excluded_specs: &[(MatchSpec, ExclusionSource)],
+) -> Vec<Exclusion> {
+    let mut exclusions: Vec<_> = excluded_specs
+        .iter()
+        .map(|(spec, source)| Exclusion {
+            spec: spec.to_string(),
+            source: *source,
+            count: 0,
+            records: Vec::new(),
+        })
+        .collect();
+    if excluded_specs.is_empty() {
+        return exclusions;
+    }
+
+    for records in reachable.iter_mut() {
+        records.retain(|record| {
+            let excluded_by = excluded_specs
+                .iter()
+                .position(|(spec, _)| spec.matches(&record.package_record));
+            let Some(i) = excluded_by else {
+                return true;
+            };
+
+            let exclusion = &mut exclusions[i];
+            exclusion.count += 1;
+            if exclusion.records.len() < MAX_EXCLUDED_RECORDS {
+                exclusion.records.push(record.package_record.to_string());
+            }
+            false
+        });
+    }
+
+    exclusions.retain(|exclusion| exclusion.count > 0);
+    exclusions
+}
+
+fn run<S: SolverFeatures>(
+    solver: &mut S,
+    reachable: &[Vec<&RepoDataRecord>],
+    specs: Vec<MatchSpec>,
+    virtual_packages: Vec<GenericVirtualPackage>,
+    strict: bool,
+    timeout: Duration,
+) -> Result<Vec<RepoDataRecord>, SolveError> {
+    let task = |available_packages| SolverTask {
+        available_packages,
+        virtual_packages,
+        specs,
+        locked_packages: Vec::new(),
+        pinned_packages: Vec::new(),
+        timeout: S::SUPPORTS_TIMEOUT.then_some(timeout),
+    };
+
+    if strict || !S::ALWAYS_STRICT {
+        return solver.solve(task(
+            reachable
+                .iter()
+                .map(|records| records.iter().copied().collect::<S::RepoData<'_>>())
+                .collect::<Vec<_>>(),
+        ));
+    }
+
+    // The solver would still leave out the records of later channels, unless all records claim to
+    // come from the same channel. The solution is mapped back to the original records.
+    let copies: Vec<Vec<RepoDataRecord>> = reachable
+        .iter()
+        .map(|records| {
+            records
+                .iter()
+                .map(|&record| RepoDataRecord {
+                    channel: String::new(),
+                    ..record.clone()
+                })
+                .collect()
+        })
+        .collect();
+    let solution = solver.solve(task(
+        copies
+            .iter()
+            .map(|records| records.iter().collect::<S::RepoData<'_>>())
+            .collect::<Vec<_>>(),
+    ))?;
+
+    let originals: HashMap<&Url, &RepoDataRecord> = reachable
+        .iter()
+        .flatten()
+        .map(|&record| (&record.url, record))
+        .collect();
+    Ok(solution
+        .iter()
+        .map(|record| originals[&record.url].clone())
+        .collect())
+}
+
+/// Lets [`reachable_records`] find the relevant records without going through all of them. It is
+/// built once for each download of the repodata, and shared by all requests using it.
+///
+/// This is only a prefilter: the solvers still build their own pool from the records it selects
+/// on every solve, because `rattler_solve` 0.19 does not accept a pool prepared in advance. That
+/// includes parsing the `depends` of the records, so parsed match specs are not cached here either.
+#[derive(Default)]
+pub struct PackageIndex {
+    /// The positions of the records of each package, by normalized package name
+    records: HashMap<String, Vec<usize>>,
+    /// The names of the dependencies of each package, for any of its versions
+    dependencies: HashMap<String, Vec<String>>,
+}
+
+impl PackageIndex {
+    pub fn new(records: &[RepoDataRecord]) -> PackageIndex {
+        let mut index = PackageIndex::default();
+        for (i, record) in records.iter().enumerate() {
+            let name = record.package_record.name.as_normalized();
+            index.records.entry(name.to_string()).or_default().push(i);
+
+            let dependencies = index.dependencies.entry(name.to_string()).or_default();
+            for dependency in &record.package_record.depends {
+                let dependency = dependency_name(dependency);
+                if !dependencies.contains(&dependency) {
+                    dependencies.push(dependency);
+                }
+            }
+        }
+
+        index
+    }
+}
+
+/// Returns the records whose names can be reached from the specs and virtual packages by following
+/// dependencies, keeping them grouped like `available_packages`. The solver can never select any
+/// of the other records, so leaving them out does not change the solution.
+///
+/// If one of the specs has no name, nothing is left out.
+pub fn reachable_records<'a>(
+    available_packages: &'a [Arc<AvailablePackages>],
+    specs: &[MatchSpec],
+    virtual_packages: &[GenericVirtualPackage],
+) -> Vec<Vec<&'a RepoDataRecord>> {
+    let mut pending = Vec::with_capacity(specs.len() + virtual_packages.len());
+    for spec in specs {
+        match &spec.name {
+            Some(name) => pending.push(name.as_normalized()),
+            None => {
+                return available_packages
+                    .iter()
+                    .map(|packages| packages.records.iter().collect())
+                    .collect()
+            }
+        }
+    }
+    pending.extend(
+        virtual_packages
+            .iter()
+            .map(|package| package.name.as_normalized()),
+    );
+
+    // Walk the dependency graph by name, without looking at versions
+    let mut reachable: HashSet<&str> = pending.iter().copied().collect();
+    while let Some(name) = pending.pop() {
+        for packages in available_packages {
+            let dependencies = packages.index.dependencies.get(name).into_iter().flatten();
+            for dependency in dependencies {
+                if reachable.insert(dependency.as_str()) {
+                    pending.push(dependency.as_str());
+                }
+            }
+        }
+    }
+
+    available_packages
+        .iter()
+        .map(|packages| {
+            // Keep the records in their original order, so the solver sees them as usual
+            let mut positions: Vec<_> = reachable
+                .iter()
+                .filter_map(|name| packages.index.records.get(*name))
+                .flatten()
+                .copied()
+                .collect();
+            positions.sort_unstable();
+
+            positions.iter().map(|&i| &packages.records[i]).collect()
+        })
+        .collect()
+}
+
+/// Gets the normalized package name from a dependency in repodata, e.g. `pyyaml` from
+/// `PyYAML >=6`. Parsing the whole match spec is much slower, and the name is all we need.
+fn dependency_name(dependency: &str) -> String {
+    let end = dependency
+        .find(|c: char| c.is_whitespace() || "<>=!~[".contains(c))
+        .unwrap_or(dependency.len());
+    dependency[..end].to_lowercase()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::available_packages_cache::RepoDataInfo;
+    use crate::cli::Solver;
+    use rattler_conda_types::{Channel, ChannelConfig, PackageName, RepoData};
+    use rstest::rstest;
+    use serde_json::json;
+    use std::str::FromStr;
+
+    /// `a` depends on a specific version of `b`, which depends on `c`. Nothing depends on `d` or
+    /// `e`, even though `d` depends on `a`.
+    fn available_packages() -> Arc<AvailablePackages> {
+        let packages = [
+            ("a", "1.0", vec!["b <2"]),
+            ("a", "2.0", vec!["b >=2", "__unix"]),
+            ("b", "1.0", vec![]),
+            ("b", "2.0", vec!["c"]),
+            ("c", "1.0", vec![]),
+            ("d", "1.0", vec!["a"]),
+            ("e", "1.0", vec![]),
+        ];
+
+        let packages: serde_json::Map<_, _> = packages
+            .into_iter()
+            .map(|(name, version, depends)| {
+                let record = json!({
+                    "name": name,
+                    "version": version,
+                    "build": "0",
+                    "build_number": 0,
+                    "subdir": "linux-64",
+                    "depends": depends,
+                });
+                (format!("{name}-{version}-0.tar.bz2"), record)
+            })
+            .collect();
+
+        let repodata: RepoData = serde_json::from_value(json!({
+            "info": { "subdir": "linux-64" },
+            "packages": packages,
+            "packages.conda": {},
+            "repodata_version": 1,
+        }))
+        .unwrap();
+
+        let channel = Channel::from_str("conda-forge", &ChannelConfig::default()).unwrap();
+        with_records(repodata.into_repo_data_records(&channel))
+    }
+
+    fn with_records(records: Vec<RepoDataRecord>) -> Arc<AvailablePackages> {
+        let info = RepoDataInfo {
+            url: "https://conda.anaconda.org/conda-forge/linux-64/".to_string(),
+            fetched_at: chrono::Utc::now(),
+            etag: None,
+            last_modified: None,
+        };
+        Arc::new(AvailablePackages::new(records, "test".to_string(), info))
+    }
+
+    /// The options most tests solve with
+    fn options() -> SolveOptions {
+        SolveOptions {
+            timeout: Duration::from_secs(20),
+            minimize_conflicts: false,
+            published_before: None,
+            exclude_undated: false,
+            excluded_specs: Vec::new(),
+            channel_priority: ChannelPriority::Flexible,
+            cancelled: CancellationToken::new(),
+        }
+    }
+
+    /// The virtual package that `a 2.0` depends on
+    fn unix() -> Vec<GenericVirtualPackage> {
+        vec![GenericVirtualPackage {
+            name: PackageName::new_unchecked("__unix"),
+            version: "0".parse().unwrap(),
+            build_string: "0".to_string(),
+        }]
+    }
+
+    fn names(records: &[RepoDataRecord]) -> Vec<String> {
+        let mut names: Vec<_> = records
+            .iter()
+            .map(|record| record.package_record.to_string())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_dependency_name() {
+        assert_eq!(dependency_name("python >=3.8,<3.13"), "python");
+        assert_eq!(dependency_name("python>=3.8"), "python");
+        assert_eq!(dependency_name("libgcc-ng"), "libgcc-ng");
+        assert_eq!(dependency_name("PyYAML >=6"), "pyyaml");
+    }
+
+    #[test]
+    fn test_index() {
+        let index = &available_packages().index;
+
+        assert_eq!(index.records["a"].len(), 2);
+        let mut dependencies = index.dependencies["a"].clone();
+        dependencies.sort();
+        assert_eq!(dependencies, vec!["__unix", "b"]);
+        assert!(index.dependencies["e"].is_empty());
+    }
+
+    #[test]
+    fn test_unreachable_records_are_left_out() {
+        let available_packages = [available_packages()];
+        let specs = vec![MatchSpec::from_str("a").unwrap()];
+
+        let reachable = reachable_records(&available_packages, &specs, &[]);
+        let mut reachable_names: Vec<_> = reachable[0]
+            .iter()
+            .map(|record| record.package_record.name.as_normalized())
+            .collect();
+        reachable_names.sort();
+
+        assert_eq!(reachable_names, vec!["a", "a", "b", "b", "c"]);
+    }
+
+    #[test]
+    fn test_nameless_spec_keeps_everything() {
+        let available_packages = [available_packages()];
+        let specs = vec![MatchSpec::default()];
+
+        let reachable = reachable_records(&available_packages, &specs, &[]);
+        assert_eq!(reachable[0].len(), available_packages[0].records.len());
+    }
+
+    #[rstest]
+    #[case::resolvo(Solver::Resolvo)]
+    #[case::libsolvc(Solver::Libsolvc)]
+    fn test_filtered_solve_matches_unfiltered_solve(#[case] solver: Solver) {
+        let available_packages = [available_packages()];
+        let specs = vec![MatchSpec::from_str("a").unwrap()];
+        let virtual_packages = unix();
+
+        let unfiltered_task = SolverTask {
+            available_packages: [&available_packages[0].records],
+            virtual_packages: virtual_packages.clone(),
+            specs: specs.clone(),
+            locked_packages: Vec::new(),
+            pinned_packages: Vec::new(),
+            timeout: None,
+        };
+        let options = options();
+        let (unfiltered, filtered) = match solver {
+            Solver::Resolvo => (
+                resolvo::Solver.solve(unfiltered_task).unwrap(),
+                solve(
+                    resolvo::Solver,
+                    &available_packages,
+                    specs,
+                    virtual_packages,
+                    &options,
+                )
+                .unwrap()
+                .packages,
+            ),
+            Solver::Libsolvc => (
+                libsolv_c::Solver.solve(unfiltered_task).unwrap(),
+                solve(
+                    libsolv_c::Solver,
+                    &available_packages,
+                    specs,
+                    virtual_packages,
+                    &options,
+                )
+                .unwrap()
+                .packages,
+            ),
+        };
+
+        assert_eq!(names(&filtered), names(&unfiltered));
+        assert_eq!(names(&filtered), vec!["a=2.0=0", "b=2.0=0", "c=1.0=0"]);
+    }
+
+    #[test]
+    fn test_minimize_conflicts() {
+        let available_packages = [available_packages()];
+        let specs = vec![
+            MatchSpec::from_str("a >=2").unwrap(),
+            MatchSpec::from_str("b <2").unwrap(),
+            MatchSpec::from_str("e").unwrap(),
+        ];
+        let virtual_packages = unix();
+        let options = SolveOptions {
+            minimize_conflicts: true,
+            ..options()
+        };
+
+        let result = solve(
+            resolvo::Solver,
+            &available_packages,
+            specs,
+            virtual_packages,
+            &options,
+        );
+        let Err(ApiError::Unsolvable(unsolvable)) = result else {
+            panic!("the environment should be unsolvable");
+        };
+
+        let minimal = unsolvable.minimal_conflict.unwrap();
+        assert_eq!(minimal.specs, vec!["a >=2", "b <2"]);
+        assert!(minimal.virtual_packages.is_empty());
+        assert_eq!(minimal.suggested_relaxation.as_deref(), Some("a >=2"));
+    }
+
+    #[test]
+    fn test_records_published_later_are_left_out() {
+        let published_before: DateTime<Utc> = "2024-01-01T00:00:00Z".parse().unwrap();
+        let mut records = available_packages().records.clone();
+        for record in &mut records {
+            // Only `a 2.0` is too new, `c` has no timestamp at all
+            let version = record.package_record.version.to_string();
+            let days = if version == "2.0" && record.package_record.name.as_normalized() == "a" {
+                1
+            } else {
+                -1
+            };
+            if record.package_record.name.as_normalized() != "c" {
+                record.package_record.timestamp =
+                    Some(published_before + chrono::Duration::days(days));
+            }
+        }
+        let available_packages = [with_records(records)];
+        let options = SolveOptions {
+            published_before: Some(published_before),
+            ..options()
+        };
+
+        let output = solve(
+            resolvo::Solver,
+            &available_packages,
+            vec![MatchSpec::from_str("a").unwrap()],
+            // Without the cutoff, `a 2.0` would be chosen
+            unix(),
+            &options,
+        )
+        .unwrap();
+        assert_eq!(names(&output.packages), vec!["a=1.0=0", "b=1.0=0"]);
+        assert!(output.exclusions.is_empty());
+
+        // The minimum package age also leaves out `c`, and reports it
+        let options = SolveOptions {
+            exclude_undated: true,
+            ..options
+        };
+        let output = solve(
+            resolvo::Solver,
+            &available_packages,
+            vec![MatchSpec::from_str("a").unwrap()],
+            unix(),
+            &options,
+        )
+        .unwrap();
+        assert_eq!(names(&output.packages), vec!["a=1.0=0", "b=1.0=0"]);
+        assert_eq!(output.exclusions.len(), 1);
+        assert_eq!(output.exclusions[0].spec, "*");
+        assert_eq!(
+            output.exclusions[0].source,
+            ExclusionSource::MissingTimestamp
+        );
+        assert_eq!(output.exclusions[0].records, vec!["c=1.0=0"]);
+    }
+
+    #[test]
+    fn test_excluded_records_are_left_out() {
+        let available_packages = [available_packages()];
+        let options = SolveOptions {
+            excluded_specs: vec![
+                (
+                    MatchSpec::from_str("b >=2").unwrap(),
+                    ExclusionSource::Policy,
+                ),
+                (MatchSpec::from_str("e").unwrap(), ExclusionSource::Request),
+            ],
+            ..options()
+        };
+
+        let output = solve(
+            resolvo::Solver,
+            &available_packages,
+            vec![MatchSpec::from_str("a").unwrap()],
+            unix(),
+            &options,
+        )
+        .unwrap();
+
+        // `e` is not reachable from `a`, so excluding it has no effect worth reporting
+        assert_eq!(names(&output.packages), vec!["a=1.0=0", "b=1.0=0"]);
+        assert_eq!(output.exclusions.len(), 1);
+        assert_eq!(output.exclusions[0].spec, "b >=2");
+        assert_eq!(output.exclusions[0].source, ExclusionSource::Policy);
+        assert_eq!(output.exclusions[0].records, vec!["b=2.0=0"]);
+    }
+
+    #[rstest]
+    #[case::strict(ChannelPriority::Strict, "a", Some(vec!["a=1.0=0", "b=1.0=0"]))]
+    #[case::flexible(ChannelPriority::Flexible, "a", Some(vec!["a=1.0=0", "b=1.0=0"]))]
+    #[case::disabled(ChannelPriority::Disabled, "a", Some(vec!["a=2.0=0", "b=2.0=0", "c=1.0=0"]))]
+    #[case::strict_unsolvable(ChannelPriority::Strict, "a >=2", None)]
+    #[case::flexible_fallback(ChannelPriority::Flexible, "a >=2", Some(vec!["a=2.0=0", "b=2.0=0", "c=1.0=0"]))]
+    fn test_channel_priority(
+        #[case] channel_priority: ChannelPriority,
+        #[case] spec: &str,
+        #[case] expected: Option<Vec<&str>>,
+    ) {
+        // The internal channel has an old `b`, which shadows the newer `b` from conda-forge
+        let conda_forge = available_packages();
+        let internal_records: Vec<_> = conda_forge
+            .records
+            .iter()
+            .filter(|record| record.package_record.to_string() == "b=1.0=0")
+            .map(|record| RepoDataRecord {
+                channel: "https://internal.example.com/".to_string(),
+                ..record.clone()
+            })
+            .collect();
+        let available_packages = [with_records(internal_records), conda_forge];
+        let options = SolveOptions {
+            channel_priority,
+            ..options()
+        };
+
+        let result = solve(
+            resolvo::Solver,
+            &available_packages,
+            vec![MatchSpec::from_str(spec).unwrap()],
+            unix(),
+            &options,
+        );
+
+        let Some(expected) = expected else {
+            assert!(matches!(result, Err(ApiError::Unsolvable(_))));
+            return;
+        };
+        let output = result.unwrap();
+        assert_eq!(names(&output.packages), expected);
+        if expected.contains(&"b=1.0=0") {
+            let b = output
+                .packages
+                .iter()
+                .find(|record| record.package_record.name.as_normalized() == "b")
+                .unwrap();
+            assert_eq!(b.channel, "https://internal.example.com/");
+        }
+    }
+}
@@ -0,0 +1,178 @@
+//! Contains the pool of threads that run the solver, so the amount of concurrent solves (and the
+//! memory they use) stays bounded
+
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+use tokio_util::sync::CancellationToken;
+use tracing::{event, Level};
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// A fixed amount of solver threads, fed by a bounded queue
+pub struct SolverPool {
+    sender: SyncSender<Job>,
+}
+
+/// Returned when the queue is full and the job was not accepted
+#[derive(Debug)]
+pub struct QueueFull;
+
+impl SolverPool {
+    /// Starts `workers` threads, which take jobs from a queue of at most `queue_size` jobs
+    pub fn new(workers: usize, queue_size: usize) -> SolverPool {
+        let (sender, receiver) = mpsc::sync_channel::<Job>(queue_size);
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        for i in 0..workers.max(1) {
+            let receiver = receiver.clone();
+            std::thread::Builder::new()
+                .name(format!("solver-{i}"))
+                .spawn(move || worker(receiver))
+                .expect("failed to spawn solver thread");
+        }
+
+        SolverPool { sender }
+    }
+
+    /// Queues the job, failing right away if the queue is full. The returned receiver yields the
+    /// job's result, together with the time it spent waiting in the queue. It yields an error if
+    /// the job panicked or was cancelled.
+    ///
+    /// Jobs that are cancelled while waiting in the queue are skipped. Jobs that are already
+    /// running are not interrupted, so long-running jobs should check `cancelled` themselves.
+    pub fn submit<T: Send + 'static>(
+        &self,
+        cancelled: CancellationToken,
+        job: impl FnOnce() -> T + Send + 'static,
+    ) -> Result<oneshot::Receiver<(T, Duration)>, QueueFull> {
+        let (result_sender, result_receiver) = oneshot::channel();
+        let queued_at = Instant::now();
+
+        let job = Box::new(move || {
+            let queue_wait = queued_at.elapsed();
+            if cancelled.is_cancelled() {
+                event!(
+                    Level::DEBUG,
+                    "Skipping solver job cancelled after waiting {queue_wait:?}"
+                );
+                return;
+            }
+
+            let result = job();
+
+            // The receiver is gone if the request was abandoned, in which case nobody cares
+            let _ = result_sender.send((result, queue_wait));
+        });
+
+        match self.sender.try_send(job) {
+            Ok(()) => Ok(result_receiver),
+            Err(TrySendError::Full(_)) => Err(QueueFull),
+            Err(TrySendError::Disconnected(_)) => unreachable!("solver threads never exit"),
+        }
+    }
+}
+
+fn worker(receiver: Arc<Mutex<Receiver<Job>>>) {
+    loop {
+        // The lock is released as soon as a job has been taken
+        let job = match receiver.lock().unwrap().recv() {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+
+        // A panicking solver must not take the thread down with it
+        if catch_unwind(AssertUnwindSafe(job)).is_err() {
+            event!(Level::ERROR, "Solver job panicked");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    #[tokio::test]
+    async fn test_submit_returns_result_and_queue_wait() {
+        let pool = SolverPool::new(1, 1);
+        let (result, queue_wait) = pool
+            .submit(CancellationToken::new(), || 42)
+            .unwrap()
+            .await
+            .unwrap();
+
+        assert_eq!(result, 42);
+        assert!(queue_wait < Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn test_submit_fails_when_queue_is_full() {
+        let pool = SolverPool::new(1, 1);
+        let (started_sender, started_receiver) = mpsc::channel();
+        let (release_sender, release_receiver) = mpsc::channel::<()>();
+
+        // Keep the only worker busy
+        let busy = pool
+            .submit(CancellationToken::new(), move || {
+                started_sender.send(()).unwrap();
+                release_receiver.recv().unwrap();
+            })
+            .unwrap();
+        started_receiver.recv().unwrap();
+
+        // One job fits in the queue, the next one doesn't
+        let queued = pool.submit(CancellationToken::new(), || ()).unwrap();
+        assert!(pool.submit(CancellationToken::new(), || ()).is_err());
+
+        release_sender.send(()).unwrap();
+        busy.await.unwrap();
+        queued.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_panicking_job_does_not_kill_worker() {
+        let pool = SolverPool::new(1, 1);
+        let token = CancellationToken::new();
+        assert!(pool
+            .submit(token.clone(), || panic!("oops"))
+            .unwrap()
+            .await
+            .is_err());
+        assert_eq!(pool.submit(token, || 42).unwrap().await.unwrap().0, 42);
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_job_is_skipped() {
+        let pool = SolverPool::new(1, 2);
+        let (started_sender, started_receiver) = mpsc::channel();
+        let (release_sender, release_receiver) = mpsc::channel::<()>();
+
+        // Keep the only worker busy while the second job is queued
+        let busy = pool
+            .submit(CancellationToken::new(), move || {
+                started_sender.send(()).unwrap();
+                release_receiver.recv().unwrap();
+            })
+            .unwrap();
+        started_receiver.recv().unwrap();
+
+        let token = CancellationToken::new();
+        let ran = Arc::new(AtomicBool::new(false));
+        let ran_clone = ran.clone();
+        let queued = pool
+            .submit(token.clone(), move || {
+                ran_clone.store(true, Ordering::SeqCst)
+            })
+            .unwrap();
+
+        token.cancel();
+        release_sender.send(()).unwrap();
+        busy.await.unwrap();
+
+        assert!(queued.await.is_err());
+        assert!(!ran.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,234 @@
+//! Contains the TLS setup used when the server terminates HTTPS connections itself
+
+use anyhow::Context;
+use axum_server::tls_rustls::RustlsConfig;
+use rustls::server::AllowAnyAuthenticatedClient;
+use rustls::{Certificate, PrivateKey, RootCertStore, ServerConfig, SignatureScheme};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tracing::{event, Level};
+
+/// The files from which the TLS configuration is loaded (and reloaded)
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsPaths {
+    /// PEM-encoded certificate chain, leaf certificate first
+    pub cert: PathBuf,
+    /// PEM-encoded private key belonging to the leaf certificate
+    pub key: PathBuf,
+    /// PEM-encoded CA certificates used to verify client certificates. When present, clients must
+    /// authenticate with a certificate signed by one of these CAs (mTLS).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_ca: Option<PathBuf>,
+}
+
+/// Loads the TLS configuration for the first time
+pub async fn load(paths: &TlsPaths) -> anyhow::Result<RustlsConfig> {
+    let config = server_config(paths).await?;
+    Ok(RustlsConfig::from_config(Arc::new(config)))
+}
+
+/// Reloads the certificates from disk every time the process receives SIGHUP. If loading fails,
+/// the previous configuration is kept, so a half-written certificate never takes the server down.
+#[cfg(unix)]
+pub async fn reload_on_sighup(config: RustlsConfig, paths: TlsPaths) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            event!(
+                Level::ERROR,
+                "Unable to listen for SIGHUP, TLS reload disabled: {e}"
+            );
+            return;
+        }
+    };
+
+    while hangup.recv().await.is_some() {
+        if let Err(e) = reload(&config, &paths).await {
+            event!(
+                Level::ERROR,
+                "Unable to reload TLS certificates, keeping the previous ones: {e:#}"
+            );
+        }
+    }
+}
+
+/// Loads the certificates from disk again and swaps them in, unless loading fails
+#[cfg(any(unix, test))]
+async fn reload(config: &RustlsConfig, paths: &TlsPaths) -> anyhow::Result<()> {
+    let new_config = server_config(paths).await?;
+    config.reload_from_config(Arc::new(new_config));
+    event!(Level::INFO, "Reloaded TLS certificates");
+    Ok(())
+}
+
+async fn server_config(paths: &TlsPaths) -> anyhow::Result<ServerConfig> {
+    let certs = read_certs(&paths.cert).await?;
+    let key = read_private_key(&paths.key).await?;
+    check_key_matches(&certs[0], &key)?;
+
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let builder = match &paths.client_ca {
+        Some(client_ca) => {
+            let mut roots = RootCertStore::empty();
+            for cert in read_certs(client_ca).await? {
+                roots.add(&cert).with_context(|| {
+                    format!("invalid client CA certificate in {}", client_ca.display())
+                })?;
+            }
+
+            builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots).boxed())
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let mut config = builder
+        .with_single_cert(certs, key)
+        .context("invalid TLS certificate or private key")?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+    Ok(config)
+}
+
+/// Makes sure the private key belongs to the leaf certificate, by signing a message with the key
+/// and verifying it with the certificate. Rustls accepts any pair, and would only fail handshakes.
+fn check_key_matches(cert: &Certificate, key: &PrivateKey) -> anyhow::Result<()> {
+    let signing_key = rustls::sign::any_supported_type(key).context("unsupported private key")?;
+    let signer = signing_key
+        .choose_scheme(&[
+            SignatureScheme::ECDSA_NISTP256_SHA256,
+            SignatureScheme::ECDSA_NISTP384_SHA384,
+            SignatureScheme::ED25519,
+            SignatureScheme::RSA_PSS_SHA256,
+        ])
+        .context("unsupported private key")?;
+    let algorithm = match signer.scheme() {
+        SignatureScheme::ECDSA_NISTP256_SHA256 => &webpki::ECDSA_P256_SHA256,
+        SignatureScheme::ECDSA_NISTP384_SHA384 => &webpki::ECDSA_P384_SHA384,
+        SignatureScheme::ED25519 => &webpki::ED25519,
+        _ => &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+    };
+
+    let message = b"rattler-server key check";
+    let signature = signer
+        .sign(message)
+        .context("unable to sign with the private key")?;
+    webpki::EndEntityCert::try_from(cert.0.as_slice())
+        .map_err(|e| anyhow::anyhow!("invalid TLS certificate: {e:?}"))?
+        .verify_signature(algorithm, message, &signature)
+        .map_err(|_| anyhow::anyhow!("the TLS certificate does not match the private key"))
+}
+
+async fn read_certs(path: &Path) -> anyhow::Result<Vec<Certificate>> {
+    let pem = read_file(path).await?;
+    let certs = rustls_pemfile::certs(&mut pem.as_slice())
+        .map(|cert| cert.map(|cert| Certificate(cert.to_vec())))
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("unable to parse certificates in {}", path.display()))?;
+
+    if certs.is_empty() {
+        anyhow::bail!("no certificates found in {}", path.display());
+    }
+
+    Ok(certs)
+}
+
+async fn read_private_key(path: &Path) -> anyhow::Result<PrivateKey> {
+    let pem = read_file(path).await?;
+    let key = rustls_pemfile::private_key(&mut pem.as_slice())
+        .with_context(|| format!("unable to parse private key in {}", path.display()))?
+        .with_context(|| format!("no private key found in {}", path.display()))?;
+
+    Ok(PrivateKey(key.secret_der().to_vec()))
+}
+
+async fn read_file(path: &Path) -> anyhow::Result<Vec<u8>> {
+    tokio::fs::read(path)
+        .await
+        .with_context(|| format!("unable to read {}", path.display()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use mktemp::Temp;
+
+    /// Writes a new self-signed certificate and its key to the directory
+    fn write_cert(dir: &Path, name: &str) -> TlsPaths {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let paths = TlsPaths {
+            cert: dir.join(format!("{name}.crt")),
+            key: dir.join(format!("{name}.key")),
+            client_ca: None,
+        };
+        std::fs::write(&paths.cert, cert.serialize_pem().unwrap()).unwrap();
+        std::fs::write(&paths.key, cert.serialize_private_key_pem()).unwrap();
+        paths
+    }
+
+    #[tokio::test]
+    async fn test_load_cert_and_key() {
+        let dir = Temp::new_dir().unwrap();
+        let paths = write_cert(&dir, "server");
+
+        let config = server_config(&paths).await.unwrap();
+        assert_eq!(
+            config.alpn_protocols,
+            vec![b"h2".to_vec(), b"http/1.1".to_vec()]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_load_with_client_ca() {
+        let dir = Temp::new_dir().unwrap();
+        let ca = write_cert(&dir, "ca");
+        let paths = TlsPaths {
+            client_ca: Some(ca.cert),
+            ..write_cert(&dir, "server")
+        };
+        assert!(server_config(&paths).await.is_ok());
+
+        // A file without certificates cannot be used to verify clients
+        let paths = TlsPaths {
+            client_ca: Some(paths.key.clone()),
+            ..paths
+        };
+        let error = server_config(&paths).await.unwrap_err();
+        assert!(
+            format!("{error:#}").contains("no certificates found"),
+            "{error:#}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_mismatched_key_is_rejected() {
+        let dir = Temp::new_dir().unwrap();
+        let paths = TlsPaths {
+            key: write_cert(&dir, "other").key,
+            ..write_cert(&dir, "server")
+        };
+
+        let error = server_config(&paths).await.unwrap_err();
+        assert!(format!("{error:#}").contains("does not match"), "{error:#}");
+    }
+
+    #[tokio::test]
+    async fn test_reload_swaps_config() {
+        let dir = Temp::new_dir().unwrap();
+        let paths = write_cert(&dir, "server");
+        let config = load(&paths).await.unwrap();
+        let before = config.get_inner();
+
+        // A broken certificate keeps the previous configuration
+        std::fs::write(&paths.cert, "garbage").unwrap();
+        assert!(reload(&config, &paths).await.is_err());
+        assert!(Arc::ptr_eq(&before, &config.get_inner()));
+
+        write_cert(&dir, "server");
+        reload(&config, &paths).await.unwrap();
+        assert!(!Arc::ptr_eq(&before, &config.get_inner()));
+    }
+}